@@ -0,0 +1,183 @@
+use image::RgbaImage;
+
+pub const DEFAULT_BLOOM_INTENSITY: f32 = 1.0;
+pub const DEFAULT_BLOOM_RADIUS: f32 = 8.0;
+/// Brightness above which pixels start to glow.
+const THRESHOLD: f32 = 0.5;
+
+/// Glow post-process for saved frames: the bright parts of a frame are blurred and added back
+/// on top of it.
+pub struct Bloom {
+    pub enabled: bool,
+    /// How strongly the blurred highlights are added back.
+    pub intensity: f32,
+    /// Standard deviation of the blur in pixels.
+    pub radius: f32,
+}
+
+impl Bloom {
+    pub fn new(enabled: bool, intensity: f32, radius: f32) -> Self {
+        Self {
+            enabled,
+            intensity,
+            radius,
+        }
+    }
+
+    /// Applies the glow to `frame` in place, if enabled.
+    pub fn apply(&self, frame: &mut RgbaImage) {
+        if !self.enabled || self.intensity <= 0.0 || self.radius <= 0.0 {
+            return;
+        }
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        // Keep only the part of each pixel above the threshold, one plane per channel.
+        let mut planes = [(); 3].map(|_| vec![0.0f32; width * height]);
+        for (i, pixel) in frame.pixels().enumerate() {
+            let [r, g, b, _] = pixel.0.map(|c| c as f32 / 255.0);
+            let brightness = r.max(g).max(b);
+            if brightness <= THRESHOLD {
+                continue;
+            }
+            let keep = (brightness - THRESHOLD) / (1.0 - THRESHOLD) / brightness;
+            planes[0][i] = r * keep;
+            planes[1][i] = g * keep;
+            planes[2][i] = b * keep;
+        }
+
+        let box_radius = box_radius(self.radius);
+        let mut scratch = vec![0.0f32; width.max(height)];
+        for plane in &mut planes {
+            gaussian_blur(plane, width, height, box_radius, &mut scratch);
+        }
+
+        for (i, pixel) in frame.pixels_mut().enumerate() {
+            for (c, plane) in planes.iter().enumerate() {
+                let value = pixel.0[c] as f32 / 255.0 + self.intensity * plane[i];
+                pixel.0[c] = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+    }
+}
+
+/// Radius of a box filter that, applied three times, has the variance of a gaussian with
+/// standard deviation `sigma`.
+fn box_radius(sigma: f32) -> usize {
+    let width = (4.0 * sigma * sigma + 1.0).sqrt();
+    ((width - 1.0) / 2.0).round().max(1.0) as usize
+}
+
+/// Approximates a gaussian blur with three box blurs in each direction, which costs the same
+/// for any radius.
+fn gaussian_blur(
+    plane: &mut [f32],
+    width: usize,
+    height: usize,
+    radius: usize,
+    scratch: &mut [f32],
+) {
+    for _ in 0..3 {
+        for y in 0..height {
+            box_blur_line(plane, y * width, 1, width, radius, scratch);
+        }
+        for x in 0..width {
+            box_blur_line(plane, x, width, height, radius, scratch);
+        }
+    }
+}
+
+/// Box blurs `len` values starting at `start` and `stride` apart, treating values past the
+/// ends as zero so light fades out at the frame's borders.
+fn box_blur_line(
+    plane: &mut [f32],
+    start: usize,
+    stride: usize,
+    len: usize,
+    radius: usize,
+    scratch: &mut [f32],
+) {
+    let at = |i: usize| start + i * stride;
+    let norm = 1.0 / (2 * radius + 1) as f32;
+    let mut sum: f32 = (0..radius.min(len)).map(|i| plane[at(i)]).sum();
+    for i in 0..len {
+        if i + radius < len {
+            sum += plane[at(i + radius)];
+        }
+        if i > radius {
+            sum -= plane[at(i - radius - 1)];
+        }
+        scratch[i] = sum * norm;
+    }
+    for (i, &value) in scratch.iter().enumerate().take(len) {
+        plane[at(i)] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn three_boxes_match_the_gaussian_variance() {
+        assert_eq!(box_radius(0.1), 1);
+        for sigma in [1.5f32, 2.0, 4.0, 8.0, 16.0] {
+            // Three boxes of width 2r + 1 have variance r (r + 1); the rounding keeps it within
+            // half a radius either way.
+            let r = box_radius(sigma) as f32;
+            let variance = sigma * sigma;
+            assert!(
+                (r * r - 0.25..=(r + 1.0) * (r + 1.0) - 0.25).contains(&variance),
+                "sigma {}: radius {}",
+                sigma,
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn box_blur_keeps_constant_lines_and_fades_at_the_ends() {
+        let (len, radius) = (20, 3);
+        let mut line = vec![2.0f32; len];
+        let mut scratch = vec![0.0; len];
+        box_blur_line(&mut line, 0, 1, len, radius, &mut scratch);
+        for &value in &line[radius..len - radius] {
+            assert!((value - 2.0).abs() < 1e-6);
+        }
+        let edge = 2.0 * (radius + 1) as f32 / (2 * radius + 1) as f32;
+        assert!((line[0] - edge).abs() < 1e-6 && (line[len - 1] - edge).abs() < 1e-6);
+    }
+
+    #[test]
+    fn box_blur_spreads_an_impulse_without_losing_light() {
+        let (len, radius) = (41, 2);
+        let mut line = vec![0.0f32; len];
+        line[20] = 1.0;
+        let mut scratch = vec![0.0; len];
+        for _ in 0..3 {
+            box_blur_line(&mut line, 0, 1, len, radius, &mut scratch);
+        }
+        let total: f32 = line.iter().sum();
+        let variance: f32 = line
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v * (i as f32 - 20.0).powi(2))
+            .sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert!((variance - (radius * (radius + 1)) as f32).abs() < 1e-4);
+    }
+
+    #[test]
+    fn constant_plane_stays_constant_under_blur() {
+        let (width, height, radius) = (40, 30, 2);
+        let mut plane = vec![0.25f32; width * height];
+        let mut scratch = vec![0.0; width.max(height)];
+        gaussian_blur(&mut plane, width, height, radius, &mut scratch);
+        // Three passes reach 3 radii in from the borders.
+        let margin = 3 * radius;
+        for y in margin..height - margin {
+            for x in margin..width - margin {
+                assert!((plane[y * width + x] - 0.25).abs() < 1e-6);
+            }
+        }
+        assert!(plane[0] < 0.25);
+    }
+}
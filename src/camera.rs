@@ -0,0 +1,83 @@
+use crate::consts::{HEIGHT, MAX_ZOOM, WIDTH};
+use crate::particle::ParticleSystem;
+use nalgebra::Vector2;
+
+/// Camera origin the window starts with and returns to on a view reset, at `MAX_ZOOM`.
+pub const HOME_ORIGIN: Vector2<f32> = Vector2::new(-100.0, -100.0);
+
+/// Share of the window left free around the particles when fitting the view to them.
+const FIT_MARGIN: f32 = 0.05;
+
+/// What the camera keeps in the middle of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CameraLock {
+    Free,
+    /// The selected particle.
+    Particle,
+    CenterOfMass,
+}
+
+impl CameraLock {
+    /// The next lock when cycling, skipping the particle lock without a selection.
+    pub fn next(self, has_selection: bool) -> Self {
+        match self {
+            CameraLock::Free if has_selection => CameraLock::Particle,
+            CameraLock::Free | CameraLock::Particle => CameraLock::CenterOfMass,
+            CameraLock::CenterOfMass => CameraLock::Free,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CameraLock::Free => "free",
+            CameraLock::Particle => "following particle",
+            CameraLock::CenterOfMass => "following center of mass",
+        }
+    }
+
+    /// World position to keep centered, if any.
+    pub fn target(
+        self,
+        particles: &ParticleSystem,
+        selected: Option<usize>,
+    ) -> Option<Vector2<f32>> {
+        match self {
+            CameraLock::Free => None,
+            CameraLock::Particle => selected.map(|idx| particles.get_position(idx)),
+            CameraLock::CenterOfMass => Some(particles.center_of_mass()),
+        }
+    }
+}
+
+/// Moves the camera so `target` is in the middle of the window.
+pub fn center_on(origin: &mut Vector2<f32>, zoom: f32, target: Vector2<f32>) {
+    *origin = Vector2::new(WIDTH / 2.0, HEIGHT / 2.0) / zoom - target;
+}
+
+/// Multiplies the zoom by `factor`, keeping the world position under `screen_pos` in place.
+pub fn zoom_at(origin: &mut Vector2<f32>, zoom: &mut f32, screen_pos: Vector2<f32>, factor: f32) {
+    let before = screen_pos / *zoom;
+    *zoom *= factor;
+    *origin += screen_pos / *zoom - before;
+}
+
+/// Origin and zoom that show all particles, or `None` without any particles.
+pub fn fit(particles: &ParticleSystem) -> Option<(Vector2<f32>, f32)> {
+    let mut min = Vector2::repeat(f32::INFINITY);
+    let mut max = Vector2::repeat(f32::NEG_INFINITY);
+    for i in 0..particles.count {
+        let pos = particles.get_position(i);
+        if pos.x.is_finite() && pos.y.is_finite() {
+            min = min.inf(&pos);
+            max = max.sup(&pos);
+        }
+    }
+    if min.x > max.x {
+        return None;
+    }
+    let size = (max - min) * (1.0 + 2.0 * FIT_MARGIN);
+    let zoom = (WIDTH / size.x).min(HEIGHT / size.y).min(MAX_ZOOM);
+    let mut origin = Vector2::zeros();
+    center_on(&mut origin, zoom, (min + max) / 2.0);
+    Some((origin, zoom))
+}
@@ -0,0 +1,257 @@
+use crate::bloom::{DEFAULT_BLOOM_INTENSITY, DEFAULT_BLOOM_RADIUS};
+use crate::coloring::{ColorMode, Colormap};
+use crate::density::{RenderMode, DEFAULT_SMOOTHING};
+use crate::export::ExportFormat;
+use crate::keys::KeyBindings;
+use crate::trails::{DEFAULT_TRAIL_LENGTH, DEFAULT_TRAIL_OPACITY};
+use rand::Rng;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "\
+Usage: gravitation-particles [OPTIONS]
+
+Options:
+  --scene <NAME>           Built-in scene to start from: galaxy, plummer, disk,
+                           collision, planets, collapse, lattice [default: galaxy]
+  --spectral-index <X>     Power spectrum slope of the lattice scene's perturbations
+                           [default: -1]
+  --seed <N>               Seed for the random scene generators, recorded in exported
+                           snapshots and file names [default: random]
+  --init <PATH>            Load initial conditions from a .csv, .npy or .npz file (or a
+                           folder of per-column .npy files) instead of the built-in scene
+  --image <PATH>           Sample particles from a picture, denser where it is brighter, and
+                           let it collapse (not with --scene or --init)
+  --group <PATH>           Particle group to drop with Alt + middle click, e.g. one saved with
+                           Shift+E
+  --replay <PATH>          Play back a recorded trajectory instead of simulating
+  --color-mode <NAME>      Particle coloring: velocity, mass, acceleration, energy, density,
+                           species, id, fixed [default: velocity, species for --image]
+  --colormap <NAME>        Colormap: classic, viridis, magma, inferno, plasma, grayscale
+                           [default: classic]
+  --render <MODE>          Draw particles as points or as a log scaled density map: points,
+                           density [default: points]
+  --smoothing <H>          Kernel smoothing length of the density map in world units, 0 bins
+                           each particle into one pixel [default: 2]
+  --hud                    Start with the statistics overlay shown
+  --trails                 Start with fading particle trails shown
+  --trail-length <N>       Number of past positions in a trail [default: 30]
+  --trail-opacity <A>      Opacity of the newest trail segment, 0 to 1 [default: 0.5]
+  --bloom                  Add a glow around bright areas of recorded and headless frames
+  --bloom-intensity <X>    Strength of the glow [default: 1]
+  --bloom-radius <PX>      Blur radius of the glow in window pixels [default: 8]
+  --keys <PATH>            Key binding file with `<action> = <keys>` lines overriding the
+                           default keys
+  --print-keys             Print the default key bindings in the key binding file format
+  --headless               Run the simulation without opening a window
+  --steps <N>              Number of steps to run in headless mode [default: 1000]
+  --export-every <N>       Export a snapshot every N steps in headless mode (0 disables)
+  --export-format <LIST>   Comma separated snapshot formats: csv, npy, npz, vtk
+                           [default: csv,npz]
+  --export-tree            Also write the quadtree (JSON and GraphViz DOT) with every snapshot
+  --trajectory <PATH>      Record the simulation state to a trajectory file in headless mode
+  --trajectory-every <N>   Record every N-th step to the trajectory [default: 1]
+  --frames-every <N>       Render a PNG frame every N steps in headless mode (0 disables)
+  --frame-size <WxH>       Size of the rendered headless frames [default: 1024x1024]
+  -h, --help               Print this help";
+
+pub struct Args {
+    pub scene: String,
+    pub seed: u64,
+    pub spectral_index: f32,
+    pub init: Option<PathBuf>,
+    pub image: Option<PathBuf>,
+    pub group: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub color_mode: Option<ColorMode>,
+    pub colormap: Colormap,
+    pub render_mode: RenderMode,
+    pub smoothing: f32,
+    pub hud: bool,
+    pub trails: bool,
+    pub trail_length: usize,
+    pub trail_opacity: f32,
+    pub bloom: bool,
+    pub bloom_intensity: f32,
+    pub bloom_radius: f32,
+    pub keys: Option<PathBuf>,
+    pub headless: bool,
+    pub steps: u64,
+    pub export_every: u64,
+    pub export_formats: Vec<ExportFormat>,
+    pub export_tree: bool,
+    pub trajectory: Option<PathBuf>,
+    pub trajectory_every: u64,
+    pub frames_every: u64,
+    pub frame_size: (u32, u32),
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            scene: "galaxy".to_string(),
+            seed: rand::thread_rng().gen(),
+            spectral_index: -1.0,
+            init: None,
+            image: None,
+            group: None,
+            replay: None,
+            color_mode: None,
+            colormap: Colormap::Classic,
+            render_mode: RenderMode::Points,
+            smoothing: DEFAULT_SMOOTHING,
+            hud: false,
+            trails: false,
+            trail_length: DEFAULT_TRAIL_LENGTH,
+            trail_opacity: DEFAULT_TRAIL_OPACITY,
+            bloom: false,
+            bloom_intensity: DEFAULT_BLOOM_INTENSITY,
+            bloom_radius: DEFAULT_BLOOM_RADIUS,
+            keys: None,
+            headless: false,
+            steps: 1000,
+            export_every: 0,
+            export_formats: vec![ExportFormat::Csv, ExportFormat::Npz],
+            export_tree: false,
+            trajectory: None,
+            trajectory_every: 1,
+            frames_every: 0,
+            frame_size: (1024, 1024),
+        }
+    }
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        let mut args = Args::default();
+        let mut raw = env::args().skip(1);
+        let mut scene_given = false;
+        while let Some(arg) = raw.next() {
+            match arg.as_str() {
+                "--scene" => {
+                    args.scene = expect_value(&arg, raw.next());
+                    scene_given = true;
+                }
+                "--seed" => args.seed = parse_number(&arg, raw.next()),
+                "--spectral-index" => args.spectral_index = parse_float(&arg, raw.next()),
+                "--init" => args.init = Some(PathBuf::from(expect_value(&arg, raw.next()))),
+                "--image" => args.image = Some(PathBuf::from(expect_value(&arg, raw.next()))),
+                "--group" => args.group = Some(PathBuf::from(expect_value(&arg, raw.next()))),
+                "--replay" => args.replay = Some(PathBuf::from(expect_value(&arg, raw.next()))),
+                "--export-tree" => args.export_tree = true,
+                "--trajectory" => {
+                    args.trajectory = Some(PathBuf::from(expect_value(&arg, raw.next())))
+                }
+                "--trajectory-every" => {
+                    args.trajectory_every = parse_number(&arg, raw.next()).max(1)
+                }
+                "--color-mode" => {
+                    let name = expect_value(&arg, raw.next());
+                    args.color_mode = Some(ColorMode::from_name(&name).unwrap_or_else(|| {
+                        exit_with_usage(&format!("Unknown color mode: {}", name))
+                    }))
+                }
+                "--colormap" => {
+                    let name = expect_value(&arg, raw.next());
+                    args.colormap = Colormap::from_name(&name)
+                        .unwrap_or_else(|| exit_with_usage(&format!("Unknown colormap: {}", name)))
+                }
+                "--render" => {
+                    let name = expect_value(&arg, raw.next());
+                    args.render_mode = RenderMode::from_name(&name).unwrap_or_else(|| {
+                        exit_with_usage(&format!("Unknown render mode: {}", name))
+                    })
+                }
+                "--smoothing" => args.smoothing = parse_float(&arg, raw.next()).max(0.0),
+                "--hud" => args.hud = true,
+                "--trails" => args.trails = true,
+                "--trail-length" => {
+                    args.trail_length = parse_number(&arg, raw.next()).max(2) as usize
+                }
+                "--trail-opacity" => {
+                    args.trail_opacity = parse_float(&arg, raw.next()).clamp(0.0, 1.0)
+                }
+                "--bloom" => args.bloom = true,
+                "--bloom-intensity" => {
+                    args.bloom_intensity = parse_float(&arg, raw.next()).max(0.0)
+                }
+                "--bloom-radius" => args.bloom_radius = parse_float(&arg, raw.next()).max(0.0),
+                "--frames-every" => args.frames_every = parse_number(&arg, raw.next()),
+                "--frame-size" => args.frame_size = parse_size(&arg, raw.next()),
+                "--keys" => args.keys = Some(PathBuf::from(expect_value(&arg, raw.next()))),
+                "--print-keys" => {
+                    print!("{}", KeyBindings::defaults().to_config());
+                    process::exit(0);
+                }
+                "--headless" => args.headless = true,
+                "--steps" => args.steps = parse_number(&arg, raw.next()),
+                "--export-every" => args.export_every = parse_number(&arg, raw.next()),
+                "--export-format" => {
+                    let value = expect_value(&arg, raw.next());
+                    args.export_formats = value
+                        .split(',')
+                        .map(|name| {
+                            ExportFormat::from_name(name.trim()).unwrap_or_else(|| {
+                                exit_with_usage(&format!("Unknown export format: {}", name))
+                            })
+                        })
+                        .collect();
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => exit_with_usage(&format!("Unknown argument: {}", arg)),
+            }
+        }
+        if args.image.is_some() && (scene_given || args.init.is_some()) {
+            exit_with_usage("--image can't be combined with --scene or --init");
+        }
+        args
+    }
+
+    /// The color mode to start with; pictures keep the colors of their pixels unless asked
+    /// otherwise.
+    pub fn initial_color_mode(&self) -> ColorMode {
+        self.color_mode.unwrap_or(if self.image.is_some() {
+            ColorMode::Species
+        } else {
+            ColorMode::Velocity
+        })
+    }
+}
+
+fn expect_value(flag: &str, value: Option<String>) -> String {
+    value.unwrap_or_else(|| exit_with_usage(&format!("Missing value for {}", flag)))
+}
+
+fn parse_number(flag: &str, value: Option<String>) -> u64 {
+    let value = expect_value(flag, value);
+    value
+        .parse()
+        .unwrap_or_else(|_| exit_with_usage(&format!("Invalid value for {}: {}", flag, value)))
+}
+
+fn parse_float(flag: &str, value: Option<String>) -> f32 {
+    let value = expect_value(flag, value);
+    value
+        .parse()
+        .unwrap_or_else(|_| exit_with_usage(&format!("Invalid value for {}: {}", flag, value)))
+}
+
+fn parse_size(flag: &str, value: Option<String>) -> (u32, u32) {
+    let value = expect_value(flag, value);
+    let size = value
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+    match size {
+        Some((w, h)) if w > 0 && h > 0 => (w, h),
+        _ => exit_with_usage(&format!("Invalid value for {}: {}", flag, value)),
+    }
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,452 @@
+use crate::particle::ParticleSystem;
+use crate::quadtree::QuadTree;
+use ggez::graphics::{self, Canvas, Color, DrawParam, Mesh, MeshData, Text, Vertex};
+use ggez::Context;
+
+/// The particle property that decides a particle's color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    Velocity,
+    Mass,
+    Acceleration,
+    KineticEnergy,
+    /// Mass over the area of the particle's quadtree leaf.
+    Density,
+    /// The generator a particle came from, or the pixel color it was sampled from.
+    Species,
+    Id,
+    Fixed,
+}
+
+const MODES: [ColorMode; 8] = [
+    ColorMode::Velocity,
+    ColorMode::Mass,
+    ColorMode::Acceleration,
+    ColorMode::KineticEnergy,
+    ColorMode::Density,
+    ColorMode::Species,
+    ColorMode::Id,
+    ColorMode::Fixed,
+];
+
+impl ColorMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        MODES
+            .into_iter()
+            .find(|mode| mode.name() == name.to_ascii_lowercase())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorMode::Velocity => "velocity",
+            ColorMode::Mass => "mass",
+            ColorMode::Acceleration => "acceleration",
+            ColorMode::KineticEnergy => "energy",
+            ColorMode::Density => "density",
+            ColorMode::Species => "species",
+            ColorMode::Id => "id",
+            ColorMode::Fixed => "fixed",
+        }
+    }
+
+    fn next(self) -> Self {
+        let idx = MODES.iter().position(|&m| m == self).unwrap();
+        MODES[(idx + 1) % MODES.len()]
+    }
+
+    /// Properties spanning several orders of magnitude are mapped on a log scale.
+    fn logarithmic(self) -> bool {
+        matches!(
+            self,
+            ColorMode::Mass
+                | ColorMode::Acceleration
+                | ColorMode::KineticEnergy
+                | ColorMode::Density
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Colormap {
+    /// Blue over green to red, with the original piecewise mapping in velocity mode.
+    Classic,
+    Viridis,
+    Magma,
+    Inferno,
+    Plasma,
+    Grayscale,
+}
+
+const COLORMAPS: [Colormap; 6] = [
+    Colormap::Classic,
+    Colormap::Viridis,
+    Colormap::Magma,
+    Colormap::Inferno,
+    Colormap::Plasma,
+    Colormap::Grayscale,
+];
+
+impl Colormap {
+    pub fn from_name(name: &str) -> Option<Self> {
+        COLORMAPS
+            .into_iter()
+            .find(|map| map.name() == name.to_ascii_lowercase())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Colormap::Classic => "classic",
+            Colormap::Viridis => "viridis",
+            Colormap::Magma => "magma",
+            Colormap::Inferno => "inferno",
+            Colormap::Plasma => "plasma",
+            Colormap::Grayscale => "grayscale",
+        }
+    }
+
+    fn next(self) -> Self {
+        let idx = COLORMAPS.iter().position(|&m| m == self).unwrap();
+        COLORMAPS[(idx + 1) % COLORMAPS.len()]
+    }
+
+    /// Evenly spaced samples of the map, from matplotlib for the perceptual ones.
+    fn anchors(self) -> &'static [[u8; 3]] {
+        match self {
+            Colormap::Classic => &[[0, 0, 255], [0, 255, 0], [255, 0, 0]],
+            Colormap::Viridis => &[
+                [68, 1, 84],
+                [71, 44, 122],
+                [59, 81, 139],
+                [44, 113, 142],
+                [33, 144, 141],
+                [39, 173, 129],
+                [92, 200, 99],
+                [170, 220, 50],
+                [253, 231, 37],
+            ],
+            Colormap::Magma => &[
+                [0, 0, 4],
+                [28, 16, 68],
+                [79, 18, 123],
+                [129, 37, 129],
+                [181, 54, 122],
+                [229, 80, 100],
+                [251, 135, 97],
+                [254, 194, 135],
+                [252, 253, 191],
+            ],
+            Colormap::Inferno => &[
+                [0, 0, 4],
+                [31, 12, 72],
+                [85, 15, 109],
+                [136, 34, 106],
+                [186, 54, 85],
+                [227, 89, 51],
+                [249, 142, 9],
+                [249, 203, 53],
+                [252, 255, 164],
+            ],
+            Colormap::Plasma => &[
+                [13, 8, 135],
+                [70, 3, 159],
+                [114, 1, 168],
+                [156, 23, 158],
+                [189, 55, 134],
+                [216, 87, 107],
+                [237, 121, 83],
+                [251, 159, 58],
+                [240, 249, 33],
+            ],
+            Colormap::Grayscale => &[[0, 0, 0], [255, 255, 255]],
+        }
+    }
+
+    /// Color at `t` in `0..=1`, linearly interpolated between the anchors.
+    pub fn sample(self, t: f32) -> Color {
+        let anchors = self.anchors();
+        let t = if t.is_finite() {
+            t.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let pos = t * (anchors.len() - 1) as f32;
+        let i = (pos as usize).min(anchors.len() - 2);
+        let frac = pos - i as f32;
+        let channel = |c: usize| {
+            let (a, b) = (anchors[i][c] as f32, anchors[i + 1][c] as f32);
+            ((a + (b - a) * frac) / 255.0).clamp(0.0, 1.0)
+        };
+        Color::new(channel(0), channel(1), channel(2), 1.0)
+    }
+}
+
+/// The velocity coloring from before colormaps existed, kept as the classic map's velocity
+/// mode: blue to red below `min + mid`, green to red above, with `mid` halfway through the range.
+fn classic_velocity(v: f32, min: f32, max: f32) -> Color {
+    let mid = (max + min) / 2.0;
+    let (value, left) = if v < min + mid {
+        ((v - min) / mid, Color::BLUE)
+    } else {
+        ((v - min - mid) / mid, Color::GREEN)
+    };
+    let right = Color::RED;
+    Color::from_rgb(
+        (((1.0 - value) * left.r + value * right.r) * 255.0) as u8,
+        (((1.0 - value) * left.g + value * right.g) * 255.0) as u8,
+        (((1.0 - value) * left.b + value * right.b) * 255.0) as u8,
+    )
+}
+
+/// Spreads category `i` over the colormap so neighbouring categories stay distinguishable.
+fn category_position(i: u32) -> f32 {
+    (i as f32 * 0.618_034).fract()
+}
+
+/// Selected color mode and colormap, and the value range the colors are scaled to.
+pub struct Coloring {
+    pub mode: ColorMode,
+    pub colormap: Colormap,
+    pub fixed_color: Color,
+    pub show_legend: bool,
+    /// Running averages of each frame's smallest and largest value since the mode changed.
+    range_min: f32,
+    range_max: f32,
+    samples: u32,
+    species_count: u32,
+}
+
+impl Coloring {
+    pub fn new(mode: ColorMode, colormap: Colormap) -> Self {
+        Self {
+            mode,
+            colormap,
+            fixed_color: Color::WHITE,
+            show_legend: true,
+            range_min: 0.0,
+            range_max: 0.0,
+            samples: 0,
+            species_count: 0,
+        }
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = self.mode.next();
+        self.samples = 0;
+    }
+
+    pub fn next_colormap(&mut self) {
+        self.colormap = self.colormap.next();
+    }
+
+    /// The property of every particle that the current mode colors by, in log10 for the
+    /// logarithmic modes. Values that cannot be shown are NaN.
+    fn values(&self, particles: &ParticleSystem, qt: &QuadTree) -> Vec<f32> {
+        let mut values: Vec<f32> = match self.mode {
+            ColorMode::Velocity => (0..particles.count)
+                .map(|i| particles.get_velocity_norm(i))
+                .collect(),
+            ColorMode::Mass => particles.mass.clone(),
+            ColorMode::Acceleration => (0..particles.count)
+                .map(|i| particles.get_net_force(i).norm() / particles.mass[i])
+                .collect(),
+            ColorMode::KineticEnergy => (0..particles.count)
+                .map(|i| 0.5 * particles.mass[i] * particles.get_velocity_norm(i).powi(2))
+                .collect(),
+            ColorMode::Density => {
+                let mut areas = vec![f32::INFINITY; particles.count];
+                qt.leaf_areas(&mut areas);
+                areas
+                    .iter()
+                    .zip(&particles.mass)
+                    .map(|(area, mass)| mass / area)
+                    .collect()
+            }
+            ColorMode::Id => particles.indices.iter().map(|&id| id as f32).collect(),
+            ColorMode::Species | ColorMode::Fixed => Vec::new(),
+        };
+        if self.mode.logarithmic() {
+            for v in &mut values {
+                *v = if *v > 0.0 { v.log10() } else { f32::NAN };
+            }
+        }
+        values
+    }
+
+    /// Computes the color of every particle, indexed like the particles.
+    pub fn colors(&mut self, particles: &ParticleSystem, qt: &QuadTree) -> Vec<Color> {
+        match self.mode {
+            ColorMode::Fixed => return vec![self.fixed_color; particles.count],
+            ColorMode::Species => {
+                self.species_count = particles.species.iter().max().map_or(0, |s| s + 1);
+                return (0..particles.count)
+                    .map(|i| {
+                        particles.color[i].unwrap_or_else(|| {
+                            self.colormap
+                                .sample(category_position(particles.species[i]))
+                        })
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+
+        let values = self.values(particles, qt);
+        let finite = values.iter().copied().filter(|v| v.is_finite());
+        let (min, max) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| {
+            (lo.min(v), hi.max(v))
+        });
+        if min <= max {
+            let n = self.samples as f32;
+            self.range_min = (self.range_min * n + min) / (n + 1.0);
+            self.range_max = (self.range_max * n + max) / (n + 1.0);
+            self.samples += 1;
+        }
+        values.iter().map(|&v| self.value_color(v)).collect()
+    }
+
+    /// Color of `v` within the current value range.
+    fn value_color(&self, v: f32) -> Color {
+        if self.mode == ColorMode::Velocity && self.colormap == Colormap::Classic {
+            return classic_velocity(v, self.range_min, self.range_max);
+        }
+        let span = (self.range_max - self.range_min).max(f32::EPSILON);
+        self.colormap.sample((v - self.range_min) / span)
+    }
+
+    fn format_value(&self, v: f32) -> String {
+        let v = if self.mode.logarithmic() {
+            10f32.powf(v)
+        } else {
+            v
+        };
+        format!("{:.3e}", v)
+    }
+
+    /// Draws a color bar with the mode, colormap and value range in the top left corner, or one
+    /// swatch per species in species mode.
+    pub fn draw_legend(&self, canvas: &mut Canvas, ctx: &mut Context) {
+        if !self.show_legend {
+            return;
+        }
+        let title = format!("{} ({})", self.mode.name(), self.colormap.name());
+        match self.mode {
+            ColorMode::Fixed => {
+                label(title, [LEGEND_X, LEGEND_Y], canvas);
+                let rect = graphics::Rect::new(LEGEND_X, LEGEND_Y + 30.0, BAR_H, BAR_H);
+                swatch(self.fixed_color, rect, canvas, ctx);
+            }
+            ColorMode::Species => {
+                label(title, [LEGEND_X, LEGEND_Y], canvas);
+                for s in 0..self.species_count.min(12) {
+                    let row = LEGEND_Y + 30.0 + s as f32 * (BAR_H + 6.0);
+                    let color = self.colormap.sample(category_position(s));
+                    swatch(
+                        color,
+                        graphics::Rect::new(LEGEND_X, row, BAR_H, BAR_H),
+                        canvas,
+                        ctx,
+                    );
+                    label(
+                        format!("species {}", s),
+                        [LEGEND_X + BAR_H + 10.0, row],
+                        canvas,
+                    );
+                }
+            }
+            _ => draw_colorbar(
+                canvas,
+                ctx,
+                |t| self.value_color(self.range_min + t * (self.range_max - self.range_min)),
+                title,
+                self.format_value(self.range_min),
+                self.format_value(self.range_max),
+            ),
+        }
+    }
+}
+
+const LEGEND_X: f32 = 20.0;
+const LEGEND_Y: f32 = 20.0;
+const BAR_W: f32 = 320.0;
+const BAR_H: f32 = 20.0;
+
+fn label(text: String, pos: [f32; 2], canvas: &mut Canvas) {
+    let mut text = Text::new(text);
+    text.set_scale(24.0);
+    canvas.draw(&text, DrawParam::default().dest(pos).color(Color::WHITE));
+}
+
+fn swatch(color: Color, rect: graphics::Rect, canvas: &mut Canvas, ctx: &mut Context) {
+    if let Ok(mesh) = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), rect, color) {
+        canvas.draw(&mesh, DrawParam::default());
+    }
+}
+
+/// Draws a titled bar of the colors `color_at` gives along `0..=1` with the values at its ends
+/// in the top left corner.
+pub fn draw_colorbar(
+    canvas: &mut Canvas,
+    ctx: &mut Context,
+    color_at: impl Fn(f32) -> Color,
+    title: String,
+    min_label: String,
+    max_label: String,
+) {
+    label(title, [LEGEND_X, LEGEND_Y], canvas);
+    let top = LEGEND_Y + 30.0;
+    const SEGMENTS: usize = 64;
+    let seg_w = BAR_W / SEGMENTS as f32;
+    let mut vertices = Vec::with_capacity(4 * SEGMENTS);
+    let mut indices = Vec::with_capacity(6 * SEGMENTS);
+    for s in 0..SEGMENTS {
+        let color = color_at(s as f32 / (SEGMENTS - 1) as f32);
+        let (left, right) = (
+            LEGEND_X + s as f32 * seg_w,
+            LEGEND_X + (s + 1) as f32 * seg_w,
+        );
+        let base = vertices.len() as u32;
+        for (x, y) in [
+            (left, top),
+            (right, top),
+            (right, top + BAR_H),
+            (left, top + BAR_H),
+        ] {
+            vertices.push(Vertex {
+                position: [x, y],
+                uv: [0.0, 0.0],
+                color: [color.r, color.g, color.b, color.a],
+            });
+        }
+        indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+    let mesh = Mesh::from_data(
+        ctx,
+        MeshData {
+            vertices: &vertices,
+            indices: &indices,
+        },
+    );
+    canvas.draw(&mesh, DrawParam::default());
+    label(min_label, [LEGEND_X, top + BAR_H + 4.0], canvas);
+    label(
+        max_label,
+        [LEGEND_X + BAR_W - 110.0, top + BAR_H + 4.0],
+        canvas,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classic_velocity_keeps_the_original_mapping() {
+        let rgb = |v: f32| classic_velocity(v, 0.0, 10.0).to_rgb();
+        assert_eq!(rgb(0.0), (0, 0, 255));
+        assert_eq!(rgb(2.5), (127, 0, 127));
+        // The switch to green sits at min + mid, not halfway through the range.
+        assert_eq!(rgb(4.9), (249, 0, 5));
+        assert_eq!(rgb(5.0), (0, 255, 0));
+        assert_eq!(rgb(7.5), (127, 127, 0));
+    }
+}
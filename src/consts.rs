@@ -4,9 +4,13 @@ pub const HEIGHT: f32 = 2048.0;
 pub const WORLD_WIDTH: f32 = 1024.0;
 pub const WORLD_HEIGHT: f32 = 1024.0;
 pub const MAX_ZOOM: f32 = WIDTH / WORLD_WIDTH+3.0;
+#[allow(dead_code)]
 pub const MOUSE_AREA: f32 = 1.0;
+#[allow(dead_code)]
 pub const LOWER_BOUND: Vector2<f32> = Vector2::new(MOUSE_AREA, MOUSE_AREA);
+#[allow(dead_code)]
 pub const UPPER_BOUND: Vector2<f32> = Vector2::new(WIDTH - MOUSE_AREA, HEIGHT - MOUSE_AREA);
 pub const G: f32 = 0.04;
 pub const SOFTENING: f32 = 2.5;
+#[allow(dead_code)]
 pub const LANES: usize = 64;
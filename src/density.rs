@@ -0,0 +1,267 @@
+use crate::coloring::Colormap;
+use crate::particle::ParticleSystem;
+use crate::raster::fit_window;
+use crate::utils::world_to_screen_coords;
+use image::{Rgba, RgbaImage};
+use nalgebra::Vector2;
+
+/// Orders of magnitude below the peak density that still get a color.
+const DECADES: f32 = 4.0;
+/// Largest kernel radius in pixels, so zooming in on a smoothed map stays affordable.
+const MAX_KERNEL_PIXELS: f32 = 48.0;
+/// Weight of the previous peak in the moving average, per frame.
+const PEAK_DECAY: f32 = 0.9;
+pub const DEFAULT_SMOOTHING: f32 = 2.0;
+
+/// How particles are put on screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    /// One circle per particle, colored by the color mode.
+    Points,
+    /// Mass binned onto a pixel grid and shown on a log scale.
+    Density,
+}
+
+impl RenderMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "points" => Some(RenderMode::Points),
+            "density" => Some(RenderMode::Density),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RenderMode::Points => "points",
+            RenderMode::Density => "density",
+        }
+    }
+
+    pub fn toggle(self) -> Self {
+        match self {
+            RenderMode::Points => RenderMode::Density,
+            RenderMode::Density => RenderMode::Points,
+        }
+    }
+}
+
+/// Cubic spline SPH kernel in 2D, unnormalized, with support `q < 2`.
+fn cubic_spline(q: f32) -> f32 {
+    if q < 1.0 {
+        1.0 - 1.5 * q * q + 0.75 * q * q * q
+    } else if q < 2.0 {
+        0.25 * (2.0 - q).powi(3)
+    } else {
+        0.0
+    }
+}
+
+/// Surface density of the particles on a grid with one cell per pixel, seen through the same
+/// camera as the particle drawing. The map shows what the window would show, scaled to fit.
+pub struct DensityMap {
+    width: u32,
+    height: u32,
+    /// Map pixels per screen pixel of the window.
+    scale: f32,
+    /// Map position of the window's top left corner.
+    offset: Vector2<f32>,
+    /// Spreads each particle's mass with an SPH kernel instead of binning it into one pixel.
+    pub smooth: bool,
+    /// Kernel smoothing length in world units.
+    pub smoothing_length: f32,
+    grid: Vec<f32>,
+    /// Scratch space for the kernel weights of one particle.
+    weights: Vec<(usize, f32)>,
+    /// Exponential moving average of each frame's log10 peak density, which anchors the tone
+    /// mapping. Starts over when the zoom or the smoothing changes.
+    peak: Option<f32>,
+    /// Zoom and smoothing the peak was measured with.
+    peak_view: (f32, bool),
+}
+
+impl DensityMap {
+    /// `smoothing_length` of 0 bins every particle into a single pixel.
+    pub fn new(width: u32, height: u32, smoothing_length: f32) -> Self {
+        let (scale, offset) = fit_window(width, height);
+        Self {
+            width,
+            height,
+            scale,
+            offset,
+            smooth: smoothing_length > 0.0,
+            smoothing_length: if smoothing_length > 0.0 {
+                smoothing_length
+            } else {
+                DEFAULT_SMOOTHING
+            },
+            grid: vec![0.0; (width * height) as usize],
+            weights: Vec::new(),
+            peak: None,
+            peak_view: (0.0, false),
+        }
+    }
+
+    fn pixel_index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+        Some((y as u32 * self.width + x as u32) as usize)
+    }
+
+    /// Spreads `amount` around `center` (in map pixels) with the kernel, normalized over the
+    /// pixels it covers so no mass is lost to the discretization.
+    fn deposit_smoothed(&mut self, center: Vector2<f32>, amount: f32, h: f32) {
+        let reach = 2.0 * h;
+        self.weights.clear();
+        let mut total = 0.0;
+        let (x0, x1) = (
+            (center.x - reach).floor() as i64,
+            (center.x + reach).ceil() as i64,
+        );
+        let (y0, y1) = (
+            (center.y - reach).floor() as i64,
+            (center.y + reach).ceil() as i64,
+        );
+        // Nothing of a kernel entirely off the grid would be shown.
+        if x1 < 0 || y1 < 0 || x0 >= self.width as i64 || y0 >= self.height as i64 {
+            return;
+        }
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let d = Vector2::new(x as f32 + 0.5, y as f32 + 0.5).metric_distance(&center);
+                let w = cubic_spline(d / h);
+                if w <= 0.0 {
+                    continue;
+                }
+                // Off-screen pixels still count towards the total, the mass there is not shown.
+                total += w;
+                if let Some(idx) = self.pixel_index(x, y) {
+                    self.weights.push((idx, w));
+                }
+            }
+        }
+        if total <= 0.0 {
+            return;
+        }
+        for &(idx, w) in &self.weights {
+            self.grid[idx] += amount * w / total;
+        }
+    }
+
+    /// Bins the particle mass onto the grid as mass per world unit squared.
+    fn deposit(&mut self, particles: &ParticleSystem, origin: Vector2<f32>, zoom: f32) {
+        self.grid.fill(0.0);
+        let pixels_per_unit = zoom * self.scale;
+        let cell_area = 1.0 / (pixels_per_unit * pixels_per_unit);
+        let h = (self.smoothing_length * pixels_per_unit).clamp(1.0, MAX_KERNEL_PIXELS / 2.0);
+        for i in 0..particles.count {
+            let pos = world_to_screen_coords(particles.get_position(i), &origin, zoom) * self.scale
+                + self.offset;
+            let density = particles.mass[i] / cell_area;
+            if self.smooth {
+                self.deposit_smoothed(pos, density, h);
+            } else if let Some(idx) = self.pixel_index(pos.x.floor() as i64, pos.y.floor() as i64) {
+                self.grid[idx] += density;
+            }
+        }
+    }
+
+    /// Renders the density map with log tone mapping; empty pixels stay black.
+    pub fn render(
+        &mut self,
+        particles: &ParticleSystem,
+        colormap: Colormap,
+        origin: Vector2<f32>,
+        zoom: f32,
+    ) -> RgbaImage {
+        self.deposit(particles, origin, zoom);
+
+        if self.peak_view != (zoom, self.smooth) {
+            self.peak = None;
+            self.peak_view = (zoom, self.smooth);
+        }
+        let max = self.grid.iter().copied().fold(0.0, f32::max);
+        if max > 0.0 {
+            let log_max = max.log10();
+            self.peak = Some(self.peak.map_or(log_max, |peak| {
+                PEAK_DECAY * peak + (1.0 - PEAK_DECAY) * log_max
+            }));
+        }
+        let floor = self.peak() - DECADES;
+
+        let mut frame = RgbaImage::new(self.width, self.height);
+        for (pixel, &density) in frame.pixels_mut().zip(&self.grid) {
+            if density <= 0.0 {
+                *pixel = Rgba([0, 0, 0, 255]);
+                continue;
+            }
+            let (r, g, b, _) = colormap
+                .sample((density.log10() - floor) / DECADES)
+                .to_rgba();
+            *pixel = Rgba([r, g, b, 255]);
+        }
+        frame
+    }
+
+    /// Densities at the bottom and top of the colormap.
+    pub fn range(&self) -> (f32, f32) {
+        (10f32.powf(self.peak() - DECADES), 10f32.powf(self.peak()))
+    }
+
+    fn peak(&self) -> f32 {
+        self.peak.unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_deposits_the_whole_amount_on_screen() {
+        let mut map = DensityMap::new(64, 64, DEFAULT_SMOOTHING);
+        map.deposit_smoothed(Vector2::new(32.3, 20.7), 5.0, 3.0);
+        let total: f32 = map.grid.iter().sum();
+        assert!((total - 5.0).abs() < 1e-4, "{}", total);
+
+        // Across the edge only the part on the grid is kept.
+        map.grid.fill(0.0);
+        map.deposit_smoothed(Vector2::new(0.0, 32.0), 5.0, 3.0);
+        let total: f32 = map.grid.iter().sum();
+        assert!((total - 2.5).abs() < 0.5, "{}", total);
+
+        map.grid.fill(0.0);
+        map.deposit_smoothed(Vector2::new(-20.0, 32.0), 5.0, 3.0);
+        assert!(map.grid.iter().all(|&d| d == 0.0));
+    }
+
+    #[test]
+    fn tone_mapping_spans_four_decades_below_the_peak() {
+        // With a zoom of 32 one map pixel is one world unit.
+        let (size, zoom) = (64, 32.0);
+        let mut map = DensityMap::new(size, size, 0.0);
+        let mut particles = ParticleSystem::new();
+        for (pos, mass) in [(10.5, 1.0), (20.5, 1e-2), (30.5, 1e-6)] {
+            particles.add_particle(Vector2::new(pos, pos), Vector2::zeros(), mass, 0.001, 0);
+        }
+        let colormap = Colormap::Viridis;
+        let frame = map.render(&particles, colormap, Vector2::zeros(), zoom);
+        let rgb = |t: f32| {
+            let (r, g, b) = colormap.sample(t).to_rgb();
+            Rgba([r, g, b, 255])
+        };
+        assert_eq!(*frame.get_pixel(10, 10), rgb(1.0));
+        assert_eq!(*frame.get_pixel(20, 20), rgb(0.5));
+        // Densities below the floor keep the bottom color, only empty pixels are black.
+        assert_eq!(*frame.get_pixel(30, 30), rgb(0.0));
+        assert_eq!(*frame.get_pixel(40, 40), Rgba([0, 0, 0, 255]));
+        let (min, max) = map.range();
+        assert!((min / 1e-4 - 1.0).abs() < 1e-4 && (max - 1.0).abs() < 1e-4);
+
+        // The peak follows a tenfold heavier particle gradually.
+        particles.mass[0] = 10.0;
+        map.render(&particles, colormap, Vector2::zeros(), zoom);
+        assert!((map.range().1 - 10f32.powf(0.1)).abs() < 1e-4);
+    }
+}
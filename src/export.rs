@@ -0,0 +1,600 @@
+use crate::particle::ParticleSystem;
+use crate::quadtree::QuadTree;
+use crate::simulation::Clock;
+use chrono::{DateTime, Local};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Npy,
+    Npz,
+    Vtk,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "npy" => Some(Self::Npy),
+            "npz" => Some(Self::Npz),
+            "vtk" | "vtp" => Some(Self::Vtk),
+            _ => None,
+        }
+    }
+}
+
+/// Column data of a snapshot, in the order it is written out.
+enum Column<'a> {
+    Float(&'a str, &'a [f32]),
+    Index(&'a str, Vec<u64>),
+    Double(&'a str, Vec<f64>),
+}
+
+fn columns(particles: &ParticleSystem) -> Vec<Column<'_>> {
+    vec![
+        Column::Index("id", particles.indices.iter().map(|&i| i as u64).collect()),
+        Column::Float("x", &particles.pos_x),
+        Column::Float("y", &particles.pos_y),
+        Column::Float("vx", &particles.vel_x),
+        Column::Float("vy", &particles.vel_y),
+        Column::Float("m", &particles.mass),
+        Column::Float("r", &particles.radius),
+    ]
+}
+
+/// The particle columns followed by the seed, simulated time and time step as one element
+/// `seed`, `time` and `dt` arrays.
+fn columns_with_metadata(particles: &ParticleSystem, seed: u64, clock: Clock) -> Vec<Column<'_>> {
+    let mut columns = columns(particles);
+    columns.push(Column::Index("seed", vec![seed]));
+    columns.push(Column::Double("time", vec![clock.time]));
+    columns.push(Column::Double("dt", vec![clock.dt as f64]));
+    columns
+}
+
+/// Creates a fresh folder named after the time and the scene's seed inside `results` for one
+/// run's snapshots.
+pub fn create_export_dir(seed: u64) -> io::Result<PathBuf> {
+    let now: DateTime<Local> = Local::now();
+    let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
+    let current_dir = std::env::current_dir()?;
+    let dir = current_dir
+        .join("results")
+        .join(format!("export_{}_seed{}", timestamp, seed));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Writes the snapshot for the step of `clock` into `dir` and returns the path that was written.
+/// Every format records the `seed` the scene was generated from, the simulated time and the
+/// time step.
+pub fn export_snapshot(
+    particles: &ParticleSystem,
+    format: ExportFormat,
+    clock: Clock,
+    seed: u64,
+    dir: &Path,
+) -> io::Result<PathBuf> {
+    let stem = format!("snapshot_{:06}", clock.steps);
+    let path = match format {
+        ExportFormat::Csv => dir.join(format!("{}.csv", stem)),
+        ExportFormat::Npy => dir.join(stem),
+        ExportFormat::Npz => dir.join(format!("{}.npz", stem)),
+        ExportFormat::Vtk => dir.join(format!("{}.vtp", stem)),
+    };
+    match format {
+        ExportFormat::Csv => write_csv(particles, seed, clock, &path)?,
+        ExportFormat::Npy => write_npy_columns(particles, seed, clock, &path)?,
+        ExportFormat::Npz => write_npz(particles, seed, clock, &path)?,
+        ExportFormat::Vtk => {
+            write_vtp(particles, seed, clock, &path)?;
+            write_pvd(dir)?;
+        }
+    }
+    Ok(path)
+}
+
+/// Writes the quadtree for `step` as `tree_<step>.json` and `tree_<step>.dot` into `dir`.
+pub fn export_tree(qt: &QuadTree, step: u64, dir: &Path) -> io::Result<PathBuf> {
+    let stem = format!("tree_{:06}", step);
+    qt.write_json(&dir.join(format!("{}.json", stem)))?;
+    qt.write_dot(&dir.join(format!("{}.dot", stem)))?;
+    Ok(dir.join(stem))
+}
+
+/// Saves a group of particles as `results/group_<timestamp>_seed<seed>.csv`, which can be
+/// loaded again with `--init` or `--group`.
+pub fn save_group(particles: &ParticleSystem, seed: u64, clock: Clock) -> io::Result<PathBuf> {
+    let now: DateTime<Local> = Local::now();
+    let path = std::env::current_dir()?.join("results").join(format!(
+        "group_{}_seed{}.csv",
+        now.format("%Y%m%d_%H%M%S"),
+        seed
+    ));
+    write_csv(particles, seed, clock, &path)?;
+    Ok(path)
+}
+
+/// Writes the columns as CSV, with the seed, simulated time and time step in a leading
+/// `# seed=<seed> time=<time> dt=<dt>` comment line.
+pub fn write_csv(
+    particles: &ParticleSystem,
+    seed: u64,
+    clock: Clock,
+    path: &Path,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "# seed={} time={} dt={}", seed, clock.time, clock.dt)?;
+    writeln!(out, "id,x,y,vx,vy,m,r")?;
+    for i in 0..particles.count {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            particles.indices[i],
+            particles.pos_x[i],
+            particles.pos_y[i],
+            particles.vel_x[i],
+            particles.vel_y[i],
+            particles.mass[i],
+            particles.radius[i],
+        )?;
+    }
+    out.flush()
+}
+
+/// Writes a VTK PolyData point cloud with velocity, mass, radius and id as point data, and the
+/// seed, simulated time and time step as field data.
+pub fn write_vtp(
+    particles: &ParticleSystem,
+    seed: u64,
+    clock: Clock,
+    path: &Path,
+) -> io::Result<()> {
+    let n = particles.count;
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "<?xml version=\"1.0\"?>")?;
+    writeln!(
+        out,
+        "<VTKFile type=\"PolyData\" version=\"0.1\" byte_order=\"LittleEndian\">"
+    )?;
+    writeln!(out, "  <PolyData>")?;
+    writeln!(out, "    <FieldData>")?;
+    writeln!(
+        out,
+        "      <DataArray type=\"UInt64\" Name=\"seed\" NumberOfTuples=\"1\" format=\"ascii\">"
+    )?;
+    writeln!(out, "        {}", seed)?;
+    writeln!(out, "      </DataArray>")?;
+    writeln!(
+        out,
+        "      <DataArray type=\"Float64\" Name=\"time\" NumberOfTuples=\"1\" format=\"ascii\">"
+    )?;
+    writeln!(out, "        {}", clock.time)?;
+    writeln!(out, "      </DataArray>")?;
+    writeln!(
+        out,
+        "      <DataArray type=\"Float32\" Name=\"dt\" NumberOfTuples=\"1\" format=\"ascii\">"
+    )?;
+    writeln!(out, "        {}", clock.dt)?;
+    writeln!(out, "      </DataArray>")?;
+    writeln!(out, "    </FieldData>")?;
+    writeln!(
+        out,
+        "    <Piece NumberOfPoints=\"{0}\" NumberOfVerts=\"{0}\" NumberOfLines=\"0\" \
+         NumberOfStrips=\"0\" NumberOfPolys=\"0\">",
+        n
+    )?;
+
+    writeln!(
+        out,
+        "      <PointData Scalars=\"mass\" Vectors=\"velocity\">"
+    )?;
+    write_data_array(
+        &mut out,
+        "Float32",
+        "velocity",
+        3,
+        (0..n).map(|i| format!("{} {} 0", particles.vel_x[i], particles.vel_y[i])),
+    )?;
+    write_data_array(&mut out, "Float32", "mass", 1, particles.mass.iter())?;
+    write_data_array(&mut out, "Float32", "radius", 1, particles.radius.iter())?;
+    write_data_array(&mut out, "UInt64", "id", 1, particles.indices.iter())?;
+    writeln!(out, "      </PointData>")?;
+
+    writeln!(out, "      <Points>")?;
+    write_data_array(
+        &mut out,
+        "Float32",
+        "position",
+        3,
+        (0..n).map(|i| format!("{} {} 0", particles.pos_x[i], particles.pos_y[i])),
+    )?;
+    writeln!(out, "      </Points>")?;
+
+    // Every point is its own vertex cell, otherwise ParaView shows nothing by default.
+    writeln!(out, "      <Verts>")?;
+    write_data_array(&mut out, "Int64", "connectivity", 1, 0..n)?;
+    write_data_array(&mut out, "Int64", "offsets", 1, 1..=n)?;
+    writeln!(out, "      </Verts>")?;
+
+    writeln!(out, "    </Piece>")?;
+    writeln!(out, "  </PolyData>")?;
+    writeln!(out, "</VTKFile>")?;
+    out.flush()
+}
+
+fn write_data_array<T: std::fmt::Display>(
+    out: &mut impl Write,
+    data_type: &str,
+    name: &str,
+    components: usize,
+    values: impl Iterator<Item = T>,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "        <DataArray type=\"{}\" Name=\"{}\" NumberOfComponents=\"{}\" format=\"ascii\">",
+        data_type, name, components
+    )?;
+    for value in values {
+        writeln!(out, "          {}", value)?;
+    }
+    writeln!(out, "        </DataArray>")
+}
+
+/// Reads the simulated time from the field data at the top of a `.vtp` snapshot.
+fn vtp_time(path: &Path) -> io::Result<Option<f64>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if line.contains("<Piece ") {
+            break;
+        }
+        if line.contains("Name=\"time\"") {
+            return Ok(lines
+                .next()
+                .transpose()?
+                .and_then(|v| v.trim().parse().ok()));
+        }
+    }
+    Ok(None)
+}
+
+/// Rewrites `series.pvd` in `dir` so it indexes every `.vtp` snapshot in the folder by its
+/// simulated time, or by its step for snapshots that don't record one.
+pub fn write_pvd(dir: &Path) -> io::Result<()> {
+    let mut snapshots: Vec<(u64, String)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let step = name
+                .strip_prefix("snapshot_")?
+                .strip_suffix(".vtp")?
+                .parse()
+                .ok()?;
+            Some((step, name))
+        })
+        .collect();
+    snapshots.sort();
+
+    let mut out = BufWriter::new(File::create(dir.join("series.pvd"))?);
+    writeln!(out, "<?xml version=\"1.0\"?>")?;
+    writeln!(
+        out,
+        "<VTKFile type=\"Collection\" version=\"0.1\" byte_order=\"LittleEndian\">"
+    )?;
+    writeln!(out, "  <Collection>")?;
+    for (step, name) in snapshots {
+        let time = vtp_time(&dir.join(&name))?.unwrap_or(step as f64);
+        writeln!(
+            out,
+            "    <DataSet timestep=\"{}\" group=\"\" part=\"0\" file=\"{}\"/>",
+            time, name
+        )?;
+    }
+    writeln!(out, "  </Collection>")?;
+    writeln!(out, "</VTKFile>")?;
+    out.flush()
+}
+
+/// Writes one `<column>.npy` file per column into the directory `dir`, plus `seed.npy`,
+/// `time.npy` and `dt.npy`.
+pub fn write_npy_columns(
+    particles: &ParticleSystem,
+    seed: u64,
+    clock: Clock,
+    dir: &Path,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for column in columns_with_metadata(particles, seed, clock) {
+        let (name, bytes) = encode_column(&column);
+        fs::write(dir.join(format!("{}.npy", name)), bytes)?;
+    }
+    Ok(())
+}
+
+/// Writes an uncompressed `.npz` archive, readable with `numpy.load`.
+pub fn write_npz(
+    particles: &ParticleSystem,
+    seed: u64,
+    clock: Clock,
+    path: &Path,
+) -> io::Result<()> {
+    let entries: Vec<(String, Vec<u8>)> = columns_with_metadata(particles, seed, clock)
+        .iter()
+        .map(|column| {
+            let (name, bytes) = encode_column(column);
+            (format!("{}.npy", name), bytes)
+        })
+        .collect();
+    let mut out = BufWriter::new(File::create(path)?);
+    write_stored_zip(&mut out, &entries)?;
+    out.flush()
+}
+
+fn encode_column<'a>(column: &Column<'a>) -> (&'a str, Vec<u8>) {
+    match column {
+        Column::Float(name, values) => {
+            let mut bytes = npy_header("<f4", values.len());
+            for v in values.iter() {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            (name, bytes)
+        }
+        Column::Index(name, values) => {
+            let mut bytes = npy_header("<u8", values.len());
+            for v in values {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            (name, bytes)
+        }
+        Column::Double(name, values) => {
+            let mut bytes = npy_header("<f8", values.len());
+            for v in values {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            (name, bytes)
+        }
+    }
+}
+
+/// Builds a version 1.0 `.npy` header for a 1D array, padded to a 64 byte boundary.
+fn npy_header(descr: &str, len: usize) -> Vec<u8> {
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        descr, len
+    );
+    // magic (6) + version (2) + header length (2) + dict + trailing newline
+    let unpadded = 10 + dict.len() + 1;
+    let padding = (64 - unpadded % 64) % 64;
+    dict.push_str(&" ".repeat(padding));
+    dict.push('\n');
+
+    let mut header = Vec::with_capacity(10 + dict.len());
+    header.extend_from_slice(b"\x93NUMPY");
+    header.extend_from_slice(&[1, 0]);
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+/// Minimal zip writer with uncompressed ("stored") entries, which is all `.npz` needs.
+fn write_stored_zip(out: &mut impl Write, entries: &[(String, Vec<u8>)]) -> io::Result<()> {
+    // DOS date for 1980-01-01, the earliest representable value.
+    const DOS_DATE: u16 = (1 << 5) | 1;
+    let mut central = Vec::new();
+    let mut offset: u32 = 0;
+
+    for (name, data) in entries {
+        let crc = crc32fast::hash(data);
+        let size = data.len() as u32;
+
+        let mut local = Vec::with_capacity(30 + name.len());
+        local.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        local.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        local.extend_from_slice(&0u16.to_le_bytes()); // flags
+        local.extend_from_slice(&0u16.to_le_bytes()); // method: stored
+        local.extend_from_slice(&0u16.to_le_bytes()); // time
+        local.extend_from_slice(&DOS_DATE.to_le_bytes());
+        local.extend_from_slice(&crc.to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        local.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        local.extend_from_slice(name.as_bytes());
+        out.write_all(&local)?;
+        out.write_all(data)?;
+
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        central.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        central.extend_from_slice(&0u16.to_le_bytes()); // flags
+        central.extend_from_slice(&0u16.to_le_bytes()); // method
+        central.extend_from_slice(&0u16.to_le_bytes()); // time
+        central.extend_from_slice(&DOS_DATE.to_le_bytes());
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&size.to_le_bytes());
+        central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        offset += local.len() as u32 + size;
+    }
+
+    out.write_all(&central)?;
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&[0; 4]); // disk numbers
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    out.write_all(&end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::load_particles;
+    use nalgebra::Vector2;
+
+    fn scratch_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gp_export_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn sample() -> ParticleSystem {
+        let mut particles = ParticleSystem::new();
+        particles.add_particle(
+            Vector2::new(1.5, -2.25),
+            Vector2::new(0.1, 0.2),
+            3.0,
+            0.5,
+            7,
+        );
+        particles.add_particle(
+            Vector2::new(-100.125, 3.0e-4),
+            Vector2::new(-0.003, 1.0e5),
+            0.01,
+            0.0,
+            2,
+        );
+        particles.add_particle(Vector2::new(0.0, 0.0), Vector2::zeros(), 1.0e6, 1.5, 40);
+        particles
+    }
+
+    fn clock() -> Clock {
+        Clock {
+            steps: 12,
+            time: 6.5,
+            dt: 0.5,
+        }
+    }
+
+    fn assert_same(expected: &ParticleSystem, actual: &ParticleSystem) {
+        assert_eq!(expected.count, actual.count);
+        assert_eq!(expected.indices, actual.indices);
+        assert_eq!(expected.pos_x, actual.pos_x);
+        assert_eq!(expected.pos_y, actual.pos_y);
+        assert_eq!(expected.vel_x, actual.vel_x);
+        assert_eq!(expected.vel_y, actual.vel_y);
+        assert_eq!(expected.mass, actual.mass);
+        assert_eq!(expected.radius, actual.radius);
+    }
+
+    fn round_trip(format: ExportFormat, name: &str) {
+        let dir = scratch_path(name);
+        fs::create_dir_all(&dir).unwrap();
+        let particles = sample();
+        let path = export_snapshot(&particles, format, clock(), 99, &dir).unwrap();
+        let mut loaded = ParticleSystem::new();
+        assert_eq!(load_particles(&mut loaded, &path).unwrap(), particles.count);
+        assert_same(&particles, &loaded);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip(ExportFormat::Csv, "csv");
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        round_trip(ExportFormat::Npy, "npy");
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        round_trip(ExportFormat::Npz, "npz");
+    }
+
+    #[test]
+    fn csv_records_seed_time_and_dt() {
+        let dir = scratch_path("csv_header");
+        fs::create_dir_all(&dir).unwrap();
+        let path = export_snapshot(&sample(), ExportFormat::Csv, clock(), 99, &dir).unwrap();
+        assert!(path.ends_with("snapshot_000012.csv"));
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().next(), Some("# seed=99 time=6.5 dt=0.5"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn npy_columns_include_time_and_dt() {
+        let dir = scratch_path("npy_time");
+        let path = export_snapshot(&sample(), ExportFormat::Npy, clock(), 99, &dir).unwrap();
+        for (name, value) in [("time", 6.5f64), ("dt", 0.5)] {
+            let bytes = fs::read(path.join(format!("{}.npy", name))).unwrap();
+            assert_eq!(&bytes[bytes.len() - 8..], &value.to_le_bytes());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pvd_indexes_snapshots_by_time() {
+        let dir = scratch_path("pvd");
+        fs::create_dir_all(&dir).unwrap();
+        // Half speed after the first snapshot, so time and step disagree.
+        for (steps, time) in [(12, 6.5), (14, 7.0)] {
+            let clock = Clock {
+                steps,
+                time,
+                dt: 0.25,
+            };
+            export_snapshot(&sample(), ExportFormat::Vtk, clock, 99, &dir).unwrap();
+        }
+        let pvd = fs::read_to_string(dir.join("series.pvd")).unwrap();
+        let timesteps: Vec<&str> = pvd
+            .lines()
+            .filter_map(|line| line.split("timestep=\"").nth(1)?.split('"').next())
+            .collect();
+        assert_eq!(timesteps, ["6.5", "7"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn npy_header_is_padded_to_64_bytes() {
+        let header = npy_header("<f4", 3);
+        assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+        assert_eq!(header.len() % 64, 0);
+        let dict_len = u16::from_le_bytes([header[8], header[9]]) as usize;
+        assert_eq!(dict_len, header.len() - 10);
+        let dict = std::str::from_utf8(&header[10..]).unwrap();
+        assert!(dict.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (3,), }"));
+        assert!(dict.ends_with('\n'));
+
+        let (name, bytes) = encode_column(&Column::Float("x", &[1.0, 2.0]));
+        assert_eq!(name, "x");
+        assert_eq!(bytes.len(), header.len() + 8);
+        assert_eq!(&bytes[bytes.len() - 4..], &2.0f32.to_le_bytes());
+    }
+
+    #[test]
+    fn stored_zip_has_crc_in_both_headers() {
+        let mut bytes = Vec::new();
+        write_stored_zip(&mut bytes, &[("a.npy".to_string(), b"hello".to_vec())]).unwrap();
+        // CRC-32 of "hello"
+        let crc = 0x3610_a686u32.to_le_bytes();
+
+        assert_eq!(&bytes[..4], &0x0403_4b50u32.to_le_bytes());
+        assert_eq!(&bytes[14..18], &crc);
+        assert_eq!(&bytes[18..22], &5u32.to_le_bytes());
+        assert_eq!(&bytes[30..35], b"a.npy");
+        assert_eq!(&bytes[35..40], b"hello");
+
+        let central = 40;
+        assert_eq!(&bytes[central..central + 4], &0x0201_4b50u32.to_le_bytes());
+        assert_eq!(&bytes[central + 16..central + 20], &crc);
+        assert_eq!(&bytes[central + 42..central + 46], &0u32.to_le_bytes());
+
+        let end = central + 46 + 5;
+        assert_eq!(&bytes[end..end + 4], &0x0605_4b50u32.to_le_bytes());
+        assert_eq!(bytes.len(), end + 22);
+    }
+}
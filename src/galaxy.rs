@@ -0,0 +1,688 @@
+use crate::consts::{G, SOFTENING};
+use crate::isotropic::{create_isotropic, sampled_mass, IsotropicProfile, Plummer};
+use crate::particle::ParticleSystem;
+use nalgebra::Vector2;
+use rand::Rng;
+use std::f64::consts::PI;
+
+const TABLE_SIZE: usize = 4096;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Spin {
+    /// The sense `create_galaxy` uses, counter-clockwise on screen.
+    CounterClockwise,
+    Clockwise,
+}
+
+impl Spin {
+    pub fn sign(self) -> f32 {
+        match self {
+            Spin::CounterClockwise => 1.0,
+            Spin::Clockwise => -1.0,
+        }
+    }
+}
+
+/// Planar Plummer bulge, sampled as particles.
+#[derive(Clone, Debug)]
+pub struct Bulge {
+    pub mass: f32,
+    pub scale_radius: f32,
+    pub particles: i32,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum Halo {
+    None,
+    /// A fixed Plummer potential that pulls on all particles but never moves.
+    Static {
+        mass: f32,
+        scale_radius: f32,
+    },
+    /// A Plummer halo sampled as particles, truncated at `truncation`.
+    Live {
+        mass: f32,
+        scale_radius: f32,
+        truncation: f32,
+        particles: i32,
+    },
+}
+
+/// A fixed external Plummer potential, applied to every particle each step.
+#[derive(Clone, Debug)]
+pub struct StaticHalo {
+    pub center: Vector2<f32>,
+    pub mass: f32,
+    pub scale_radius: f32,
+}
+
+impl StaticHalo {
+    pub fn acceleration(&self, pos: Vector2<f32>) -> Vector2<f32> {
+        let d = self.center - pos;
+        // Softened the same way as `Plummer::relative_potential`.
+        let a = self.scale_radius + SOFTENING;
+        let r_sq = d.norm_squared() + a * a;
+        d * (G * self.mass / (r_sq * r_sq.sqrt()))
+    }
+
+    /// Potential energy per unit mass at `pos`, matching `acceleration`.
+    pub fn potential(&self, pos: Vector2<f32>) -> f32 {
+        let a = self.scale_radius + SOFTENING;
+        -G * self.mass / ((self.center - pos).norm_squared() + a * a).sqrt()
+    }
+}
+
+/// Exponential disk galaxy with an optional central mass, bulge and halo.
+#[derive(Clone, Debug)]
+pub struct DiskGalaxy {
+    pub center: Vector2<f32>,
+    pub initial_vel: Vector2<f32>,
+    pub spin: Spin,
+    /// Mass of the disk particles, the disk is truncated at `truncation` scale lengths.
+    pub disk_mass: f32,
+    pub scale_length: f32,
+    pub truncation: f32,
+    pub disk_particles: i32,
+    /// Toomre stability parameter that sets the radial velocity dispersion.
+    pub toomre_q: f32,
+    /// Point mass in the center like the sun of `create_galaxy`, skipped when zero.
+    pub central_mass: f32,
+    pub bulge: Option<Bulge>,
+    pub halo: Halo,
+}
+
+impl Default for DiskGalaxy {
+    fn default() -> Self {
+        Self {
+            center: Vector2::new(0.0, 0.0),
+            initial_vel: Vector2::new(0.0, 0.0),
+            spin: Spin::CounterClockwise,
+            disk_mass: 20.0,
+            scale_length: 25.0,
+            truncation: 5.0,
+            disk_particles: 4000,
+            toomre_q: 1.5,
+            central_mass: 0.0,
+            bulge: Some(Bulge {
+                mass: 5.0,
+                scale_radius: 5.0,
+                particles: 500,
+            }),
+            halo: Halo::Static {
+                mass: 60.0,
+                scale_radius: 80.0,
+            },
+        }
+    }
+}
+
+impl DiskGalaxy {
+    fn bulge_profile(&self) -> Option<Plummer> {
+        self.bulge
+            .as_ref()
+            .map(|bulge| Plummer::new(bulge.mass, bulge.scale_radius))
+    }
+
+    fn halo_profile(&self) -> Option<Plummer> {
+        match self.halo {
+            Halo::None => None,
+            Halo::Static { mass, scale_radius } => Some(Plummer::new(mass, scale_radius)),
+            Halo::Live {
+                mass,
+                scale_radius,
+                truncation,
+                ..
+            } => {
+                let mut profile = Plummer::new(mass, scale_radius);
+                profile.truncation = truncation as f64;
+                Some(profile)
+            }
+        }
+    }
+
+    fn disk_radius(&self) -> f64 {
+        (self.truncation * self.scale_length) as f64
+    }
+
+    /// Mass of the particles the galaxy is built from, everything but a static halo. Less than
+    /// the component masses where the isotropic sampling truncates the bulge or halo profile.
+    pub fn moving_mass(&self) -> f32 {
+        let mut mass = self.central_mass.max(0.0) as f64;
+        if self.disk_particles > 0 {
+            mass += self.disk_mass as f64;
+        }
+        if let (Some(bulge), Some(profile)) = (&self.bulge, self.bulge_profile()) {
+            if bulge.particles > 0 {
+                mass += sampled_mass(&profile);
+            }
+        }
+        if let (Halo::Live { particles, .. }, Some(profile)) = (&self.halo, self.halo_profile()) {
+            if *particles > 0 {
+                mass += sampled_mass(&profile);
+            }
+        }
+        mass as f32
+    }
+
+    /// Copy with every mass component multiplied by `factor`.
+    fn with_scaled_mass(&self, factor: f32) -> Self {
+        let mut galaxy = self.clone();
+        galaxy.disk_mass *= factor;
+        galaxy.central_mass *= factor;
+        if let Some(bulge) = &mut galaxy.bulge {
+            bulge.mass *= factor;
+        }
+        match &mut galaxy.halo {
+            Halo::Static { mass, .. } | Halo::Live { mass, .. } => *mass *= factor,
+            Halo::None => {}
+        }
+        galaxy
+    }
+}
+
+/// Two disk galaxies placed on a Keplerian two-body orbit in their center-of-mass frame.
+///
+/// The `center` and `initial_vel` of the galaxy specs are ignored, the pair as a whole sits at
+/// `center` and moves with `initial_vel`.
+#[derive(Clone, Debug)]
+pub struct GalaxyCollision {
+    pub primary: DiskGalaxy,
+    pub secondary: DiskGalaxy,
+    /// Secondary to primary mass; the secondary's masses are rescaled to match it.
+    pub mass_ratio: f32,
+    pub pericenter: f32,
+    /// 0 is circular, below 1 bound, 1 parabolic and above 1 hyperbolic.
+    pub eccentricity: f32,
+    /// Starting distance between the two centers on the way in, clamped to the orbit.
+    pub separation: f32,
+    /// Sense in which the secondary orbits the primary, compare with the galaxies' `spin`.
+    pub orbit_spin: Spin,
+    /// Direction of the pericenter as seen from the primary, in radians.
+    pub pericenter_angle: f32,
+    pub center: Vector2<f32>,
+    pub initial_vel: Vector2<f32>,
+}
+
+impl Default for GalaxyCollision {
+    fn default() -> Self {
+        let galaxy = DiskGalaxy {
+            disk_particles: 3000,
+            scale_length: 15.0,
+            central_mass: 5.0,
+            bulge: Some(Bulge {
+                mass: 3.0,
+                scale_radius: 3.0,
+                particles: 300,
+            }),
+            halo: Halo::None,
+            ..DiskGalaxy::default()
+        };
+        Self {
+            primary: galaxy.clone(),
+            secondary: galaxy,
+            mass_ratio: 0.5,
+            pericenter: 60.0,
+            eccentricity: 1.0,
+            separation: 250.0,
+            orbit_spin: Spin::CounterClockwise,
+            pericenter_angle: 0.0,
+            center: Vector2::new(0.0, 0.0),
+            initial_vel: Vector2::new(0.0, 0.0),
+        }
+    }
+}
+
+/// Returns the relative position and velocity of the secondary with respect to the primary.
+fn two_body_state(collision: &GalaxyCollision, total_mass: f32) -> (Vector2<f32>, Vector2<f32>) {
+    let e = collision.eccentricity.max(0.0) as f64;
+    let rp = collision.pericenter as f64;
+    let p = rp * (1.0 + e);
+    let mut r = (collision.separation as f64).max(rp);
+    if e < 1.0 {
+        r = r.min(p / (1.0 - e));
+    }
+    // True anomaly on the incoming branch, so the galaxies approach each other.
+    let nu = if e > 0.0 {
+        -((p / r - 1.0) / e).clamp(-1.0, 1.0).acos()
+    } else {
+        0.0
+    };
+    let r = p / (1.0 + e * nu.cos());
+
+    let sign = collision.orbit_spin.sign() as f64;
+    let phi = collision.pericenter_angle as f64 - sign * nu;
+    let radial = Vector2::new(phi.cos(), phi.sin());
+    // Same sense as the disk rotation in `create_disk_galaxy`.
+    let tangent = Vector2::new(radial.y, -radial.x) * sign;
+    let v_scale = (G as f64 * total_mass as f64 / p).sqrt();
+    let vel = radial * (v_scale * e * nu.sin()) + tangent * (v_scale * (1.0 + e * nu.cos()));
+    ((radial * r).cast::<f32>(), vel.cast::<f32>())
+}
+
+/// Builds both galaxies of `collision`, see [`GalaxyCollision`].
+///
+/// Static halos cannot follow a galaxy along its orbit, so both galaxies need a live halo or none.
+pub fn create_galaxy_collision(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    collision: &GalaxyCollision,
+) {
+    assert!(
+        !matches!(collision.primary.halo, Halo::Static { .. })
+            && !matches!(collision.secondary.halo, Halo::Static { .. }),
+        "Galaxies in a collision need live halos or none"
+    );
+    let primary_mass = collision.primary.moving_mass();
+    let unscaled_secondary_mass = collision.secondary.moving_mass();
+    assert!(
+        primary_mass > 0.0 && unscaled_secondary_mass > 0.0,
+        "Galaxies in a collision need particles with mass"
+    );
+    assert!(
+        collision.mass_ratio > 0.0,
+        "The mass ratio of a collision has to be positive"
+    );
+    let secondary_mass = collision.mass_ratio * primary_mass;
+    let secondary = collision
+        .secondary
+        .with_scaled_mass(secondary_mass / unscaled_secondary_mass);
+    let total_mass = primary_mass + secondary_mass;
+
+    let (rel_pos, rel_vel) = two_body_state(collision, total_mass);
+    let primary_share = secondary_mass / total_mass;
+    let secondary_share = primary_mass / total_mass;
+
+    create_disk_galaxy(
+        particles,
+        rng,
+        &DiskGalaxy {
+            center: collision.center - rel_pos * primary_share,
+            initial_vel: collision.initial_vel - rel_vel * primary_share,
+            ..collision.primary.clone()
+        },
+    );
+    // Both galaxies number their particles from 0, the secondary goes on from the primary.
+    let first_secondary = particles.count;
+    let next_id = particles.next_id();
+    create_disk_galaxy(
+        particles,
+        rng,
+        &DiskGalaxy {
+            center: collision.center + rel_pos * secondary_share,
+            initial_vel: collision.initial_vel + rel_vel * secondary_share,
+            ..secondary
+        },
+    );
+    particles.offset_ids(first_secondary, next_id);
+}
+
+/// Mass fraction of an untruncated exponential disk inside `x` scale lengths.
+fn exponential_mass_fraction(x: f64) -> f64 {
+    1.0 - (1.0 + x) * (-x).exp()
+}
+
+/// In-plane gravitational field of all components, using the enclosed mass for the disk.
+struct GalaxyPotential {
+    r_max: f64,
+    /// Inward acceleration per radius.
+    field: Vec<f64>,
+    /// Relative potential `-Φ` per radius.
+    potential: Vec<f64>,
+}
+
+impl GalaxyPotential {
+    fn new(galaxy: &DiskGalaxy) -> Self {
+        let eps_sq = (SOFTENING * SOFTENING) as f64;
+        let g = G as f64;
+        let h = galaxy.scale_length as f64;
+        let disk_norm = exponential_mass_fraction(galaxy.truncation as f64);
+        let spheroids: Vec<Plummer> = galaxy
+            .bulge_profile()
+            .into_iter()
+            .chain(galaxy.halo_profile())
+            .collect();
+
+        let r_max = spheroids
+            .iter()
+            .map(|p| p.truncation)
+            .fold(galaxy.disk_radius(), f64::max)
+            * 2.0;
+        let radius = |i: usize| r_max * i as f64 / (TABLE_SIZE - 1) as f64;
+
+        let field: Vec<f64> = (0..TABLE_SIZE)
+            .map(|i| {
+                let r = radius(i);
+                let point = |mass: f64| g * mass * r / (r * r + eps_sq).powf(1.5);
+                let disk_mass = galaxy.disk_mass as f64
+                    * exponential_mass_fraction((r / h).min(galaxy.truncation as f64))
+                    / disk_norm;
+                let spheroids: f64 = spheroids
+                    .iter()
+                    .map(|p| {
+                        let a = p.scale_radius + SOFTENING as f64;
+                        g * p.mass * r / (r * r + a * a).powf(1.5)
+                    })
+                    .sum();
+                point(disk_mass) + point(galaxy.central_mass as f64) + spheroids
+            })
+            .collect();
+
+        // Beyond the table everything looks like a point mass.
+        let total_mass = galaxy.disk_mass as f64
+            + galaxy.central_mass as f64
+            + spheroids.iter().map(|p| p.mass).sum::<f64>();
+        let mut potential = vec![0.0; TABLE_SIZE];
+        potential[TABLE_SIZE - 1] = g * total_mass / (r_max * r_max + eps_sq).sqrt();
+        let dr = radius(1);
+        for i in (0..TABLE_SIZE - 1).rev() {
+            potential[i] = potential[i + 1] + 0.5 * (field[i] + field[i + 1]) * dr;
+        }
+
+        Self {
+            r_max,
+            field,
+            potential,
+        }
+    }
+
+    fn interpolate(&self, table: &[f64], r: f64) -> f64 {
+        let x = (r / self.r_max).clamp(0.0, 1.0) * (TABLE_SIZE - 1) as f64;
+        let i = (x as usize).min(TABLE_SIZE - 2);
+        let t = x - i as f64;
+        table[i] * (1.0 - t) + table[i + 1] * t
+    }
+
+    fn circular_velocity(&self, r: f64) -> f64 {
+        (r * self.interpolate(&self.field, r)).max(0.0).sqrt()
+    }
+
+    /// Epicyclic frequency, `κ² = 2v/r² (v + r dv/dr)`.
+    fn kappa(&self, r: f64) -> f64 {
+        let dr = self.r_max / TABLE_SIZE as f64;
+        let v = self.circular_velocity(r);
+        let dv = (self.circular_velocity(r + dr) - self.circular_velocity((r - dr).max(0.0)))
+            / (r + dr - (r - dr).max(0.0));
+        (2.0 * v / (r * r) * (v + r * dv)).max(0.0).sqrt()
+    }
+}
+
+/// A Plummer component moving in the potential of the whole galaxy.
+struct EmbeddedPlummer<'a> {
+    profile: &'a Plummer,
+    potential: &'a GalaxyPotential,
+}
+
+impl IsotropicProfile for EmbeddedPlummer<'_> {
+    fn surface_density(&self, r: f64) -> f64 {
+        self.profile.surface_density(r)
+    }
+
+    fn relative_potential(&self, r: f64) -> f64 {
+        self.potential.interpolate(&self.potential.potential, r)
+    }
+
+    fn truncation_radius(&self) -> f64 {
+        self.profile.truncation
+    }
+}
+
+pub fn gaussian(rng: &mut impl Rng) -> f64 {
+    // Box-Muller transform.
+    let u1: f64 = rng.gen_range(f64::MIN_POSITIVE..1.0);
+    let u2: f64 = rng.gen_range(0.0..1.0);
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+/// Builds an exponential disk whose rotation comes from the mass enclosed by all components,
+/// with radial dispersion from the Toomre Q and an asymmetric-drift corrected mean rotation.
+/// Bulge and live halo particles are sampled isotropically in the combined potential.
+pub fn create_disk_galaxy(particles: &mut ParticleSystem, rng: &mut impl Rng, galaxy: &DiskGalaxy) {
+    let potential = GalaxyPotential::new(galaxy);
+    particles.start_species();
+    let first = particles.count;
+
+    let h = galaxy.scale_length as f64;
+    let disk_norm = exponential_mass_fraction(galaxy.truncation as f64);
+    let particle_mass = galaxy.disk_mass / galaxy.disk_particles.max(1) as f32;
+    for i in 0..galaxy.disk_particles {
+        // Invert the enclosed mass by bisection.
+        let target = rng.gen_range(0.0..1.0) * disk_norm;
+        let (mut lo, mut hi) = (0.0, galaxy.truncation as f64);
+        for _ in 0..40 {
+            let mid = 0.5 * (lo + hi);
+            if exponential_mass_fraction(mid) < target {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let r = (0.5 * (lo + hi) * h).max(1e-3);
+
+        let surface_density =
+            galaxy.disk_mass as f64 * (-r / h).exp() / (2.0 * PI * h * h * disk_norm);
+        let v_c = potential.circular_velocity(r);
+        let omega = v_c / r;
+        let kappa = potential.kappa(r);
+        let sigma_r = if kappa > 0.0 {
+            galaxy.toomre_q as f64 * 3.36 * G as f64 * surface_density / kappa
+        } else {
+            0.0
+        };
+        let sigma_phi = if omega > 0.0 {
+            sigma_r * kappa / (2.0 * omega)
+        } else {
+            sigma_r
+        };
+        let drift = sigma_r * sigma_r * (1.0 - kappa * kappa / (4.0 * omega * omega) - 2.0 * r / h);
+        let v_phi = (v_c * v_c + drift).max(0.0).sqrt();
+
+        let angle = rng.gen_range(0.0..2.0 * PI);
+        let radial = Vector2::new(angle.cos(), angle.sin());
+        let tangent = Vector2::new(radial.y, -radial.x) * galaxy.spin.sign() as f64;
+        let vel =
+            radial * (sigma_r * gaussian(rng)) + tangent * (v_phi + sigma_phi * gaussian(rng));
+        particles.add_particle(
+            (radial * r).cast::<f32>() + galaxy.center,
+            vel.cast::<f32>() + galaxy.initial_vel,
+            particle_mass,
+            0.001,
+            i as usize,
+        );
+    }
+
+    // The components are numbered on from the disk, `create_isotropic` starts from 0.
+    if let (Some(bulge), Some(profile)) = (&galaxy.bulge, galaxy.bulge_profile()) {
+        let embedded = EmbeddedPlummer {
+            profile: &profile,
+            potential: &potential,
+        };
+        let start = particles.count;
+        create_isotropic(
+            particles,
+            rng,
+            galaxy.center,
+            galaxy.initial_vel,
+            &embedded,
+            bulge.particles,
+        );
+        particles.offset_ids(start, start - first);
+    }
+
+    match (&galaxy.halo, galaxy.halo_profile()) {
+        (Halo::Live { particles: n, .. }, Some(profile)) => {
+            let embedded = EmbeddedPlummer {
+                profile: &profile,
+                potential: &potential,
+            };
+            let start = particles.count;
+            create_isotropic(
+                particles,
+                rng,
+                galaxy.center,
+                galaxy.initial_vel,
+                &embedded,
+                *n,
+            );
+            particles.offset_ids(start, start - first);
+        }
+        (Halo::Static { mass, scale_radius }, _) => particles.static_halos.push(StaticHalo {
+            center: galaxy.center,
+            mass: *mass,
+            scale_radius: *scale_radius,
+        }),
+        _ => {}
+    }
+
+    if galaxy.central_mass > 0.0 {
+        particles.start_species();
+        particles.add_particle(
+            galaxy.center,
+            galaxy.initial_vel,
+            galaxy.central_mass,
+            1.5,
+            particles.count - first,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Radius, radial and tangential velocity of particle `i` in the galaxy's frame.
+    fn polar(particles: &ParticleSystem, galaxy: &DiskGalaxy, i: usize) -> (f64, f64, f64) {
+        let pos = (particles.get_position(i) - galaxy.center).cast::<f64>();
+        let vel = (Vector2::new(particles.vel_x[i], particles.vel_y[i]) - galaxy.initial_vel)
+            .cast::<f64>();
+        let r = pos.norm();
+        let v_phi = (vel.x * pos.y - vel.y * pos.x) / r * galaxy.spin.sign() as f64;
+        (r, vel.dot(&pos) / r, v_phi)
+    }
+
+    #[test]
+    fn particles_carry_the_moving_mass() {
+        let galaxy = DiskGalaxy {
+            disk_particles: 1000,
+            central_mass: 4.0,
+            halo: Halo::Live {
+                mass: 40.0,
+                scale_radius: 60.0,
+                truncation: 300.0,
+                particles: 1000,
+            },
+            ..DiskGalaxy::default()
+        };
+        let mut particles = ParticleSystem::new();
+        create_disk_galaxy(&mut particles, &mut StdRng::seed_from_u64(1), &galaxy);
+        let total: f64 = particles.mass.iter().map(|&m| m as f64).sum();
+        let moving = galaxy.moving_mass() as f64;
+        assert!(
+            (total / moving - 1.0).abs() < 1e-4,
+            "{} vs {}",
+            total,
+            moving
+        );
+
+        // Disk and central mass in full, the Kuzmin bulge and halo inside their truncation.
+        let kuzmin = |mass: f64, a: f64, r: f64| mass * (1.0 - a / (r * r + a * a).sqrt());
+        let expected = 20.0 + 4.0 + kuzmin(5.0, 5.0, 100.0) + kuzmin(40.0, 60.0, 300.0);
+        assert!(
+            (moving / expected - 1.0).abs() < 1e-3,
+            "{} vs {}",
+            moving,
+            expected
+        );
+    }
+
+    #[test]
+    fn collision_ids_are_unique() {
+        let mut collision = GalaxyCollision::default();
+        for galaxy in [&mut collision.primary, &mut collision.secondary] {
+            galaxy.disk_particles = 200;
+            galaxy.bulge.as_mut().unwrap().particles = 50;
+        }
+        let mut particles = ParticleSystem::new();
+        create_galaxy_collision(&mut particles, &mut StdRng::seed_from_u64(4), &collision);
+        assert_eq!(particles.count, 2 * (200 + 50 + 1));
+        let mut ids = particles.indices.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), particles.count);
+    }
+
+    #[test]
+    fn cold_disk_follows_the_rotation_curve() {
+        let galaxy = DiskGalaxy {
+            toomre_q: 0.0,
+            bulge: None,
+            central_mass: 10.0,
+            spin: Spin::Clockwise,
+            ..DiskGalaxy::default()
+        };
+        let mut particles = ParticleSystem::new();
+        create_disk_galaxy(&mut particles, &mut StdRng::seed_from_u64(2), &galaxy);
+
+        // Enclosed disk mass plus the central mass and the static halo, all softened.
+        let (g, eps) = (G as f64, SOFTENING as f64);
+        let h = galaxy.scale_length as f64;
+        let halo_a = 80.0 + eps;
+        let norm = exponential_mass_fraction(galaxy.truncation as f64);
+        for i in 0..galaxy.disk_particles as usize {
+            let (r, v_r, v_phi) = polar(&particles, &galaxy, i);
+            assert!(v_r.abs() < 1e-4, "radial velocity {} at r = {}", v_r, r);
+            // The potential table is too coarse to resolve the softened core.
+            if r < 2.0 * eps {
+                continue;
+            }
+            let enclosed = 10.0 + 20.0 * exponential_mass_fraction(r / h) / norm;
+            let v_c_sq = g * enclosed * r * r / (r * r + eps * eps).powf(1.5)
+                + g * 60.0 * r * r / (r * r + halo_a * halo_a).powf(1.5);
+            assert!(
+                (v_phi / v_c_sq.sqrt() - 1.0).abs() < 1e-2,
+                "v_phi {} vs v_c {} at r = {}",
+                v_phi,
+                v_c_sq.sqrt(),
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn disk_dispersion_recovers_toomre_q() {
+        let galaxy = DiskGalaxy {
+            disk_particles: 40000,
+            bulge: None,
+            ..DiskGalaxy::default()
+        };
+        let mut particles = ParticleSystem::new();
+        create_disk_galaxy(&mut particles, &mut StdRng::seed_from_u64(3), &galaxy);
+
+        // A thin annulus around two scale lengths.
+        let h = galaxy.scale_length as f64;
+        let (inner, outer) = (1.9 * h, 2.1 * h);
+        let (mut count, mut v_r_sq) = (0, 0.0);
+        for i in 0..galaxy.disk_particles as usize {
+            let (r, v_r, _) = polar(&particles, &galaxy, i);
+            if (inner..outer).contains(&r) {
+                count += 1;
+                v_r_sq += v_r * v_r;
+            }
+        }
+        let sigma_r = (v_r_sq / count as f64).sqrt();
+
+        let mass = count as f64 * (galaxy.disk_mass / galaxy.disk_particles as f32) as f64;
+        let surface_density = mass / (PI * (outer * outer - inner * inner));
+        let kappa = GalaxyPotential::new(&galaxy).kappa(2.0 * h);
+        let q = sigma_r * kappa / (3.36 * G as f64 * surface_density);
+        assert!(
+            (q / galaxy.toomre_q as f64 - 1.0).abs() < 0.05,
+            "Q = {} from {} particles",
+            q,
+            count
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use crate::bloom::Bloom;
+use crate::cli::Args;
+use crate::coloring::Coloring;
+use crate::density::{DensityMap, RenderMode};
+use crate::export::{create_export_dir, export_snapshot, export_tree};
+use crate::particle::ParticleSystem;
+use crate::raster::{create_frames_dir, fit_window, Rasterizer};
+use crate::simulation::Simulation;
+use crate::trajectory::TrajectoryWriter;
+use nalgebra::Vector2;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+enum FrameRenderer {
+    Points(Rasterizer),
+    Density(DensityMap),
+}
+
+/// Renders PNG frames through the window's camera into their own folder.
+struct FrameWriter {
+    dir: PathBuf,
+    renderer: FrameRenderer,
+    bloom: Bloom,
+    coloring: Coloring,
+    origin: Vector2<f32>,
+    zoom: f32,
+    frames: u64,
+}
+
+impl FrameWriter {
+    fn write(&mut self, sim: &Simulation) {
+        let mut frame = match &mut self.renderer {
+            FrameRenderer::Points(rasterizer) => {
+                let colors = self.coloring.colors(&sim.particles, &sim.qt);
+                rasterizer.render(&sim.particles, &colors, self.origin, self.zoom)
+            }
+            FrameRenderer::Density(map) => map.render(
+                &sim.particles,
+                self.coloring.colormap,
+                self.origin,
+                self.zoom,
+            ),
+        };
+        self.bloom.apply(&mut frame);
+        // Numbered by frame rather than step so ffmpeg can read them as a sequence.
+        let path = self.dir.join(format!("frame_{:06}.png", self.frames));
+        match frame.save(&path) {
+            Ok(_) => self.frames += 1,
+            Err(e) => eprintln!("Error saving frame {}: {}", path.display(), e),
+        }
+    }
+}
+
+pub fn run(args: &Args, particles: ParticleSystem, origin: Vector2<f32>, zoom: f32) {
+    let mut sim = Simulation::new(particles);
+    let export_dir = if args.export_every > 0 {
+        match create_export_dir(args.seed) {
+            Ok(dir) => {
+                println!("Exporting snapshots to {}", dir.display());
+                Some(dir)
+            }
+            Err(e) => {
+                eprintln!("Error creating export folder: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut trajectory =
+        args.trajectory
+            .as_ref()
+            .and_then(|path| match TrajectoryWriter::create(path, args.seed) {
+                Ok(writer) => {
+                    println!("Recording trajectory to {}", path.display());
+                    Some(writer)
+                }
+                Err(e) => {
+                    eprintln!("Error creating trajectory file: {}", e);
+                    None
+                }
+            });
+
+    let mut frames = if args.frames_every > 0 {
+        match create_frames_dir(args.seed) {
+            Ok(dir) => {
+                println!(
+                    "Rendering {} frames to {}",
+                    args.render_mode.name(),
+                    dir.display()
+                );
+                let (width, height) = args.frame_size;
+                let renderer = match args.render_mode {
+                    RenderMode::Points => FrameRenderer::Points(Rasterizer::new(width, height)),
+                    RenderMode::Density => {
+                        FrameRenderer::Density(DensityMap::new(width, height, args.smoothing))
+                    }
+                };
+                // The bloom radius is given for the window, scale it with the frame.
+                let bloom_radius = args.bloom_radius * fit_window(width, height).0;
+                Some(FrameWriter {
+                    dir,
+                    renderer,
+                    bloom: Bloom::new(args.bloom, args.bloom_intensity, bloom_radius),
+                    coloring: Coloring::new(args.initial_color_mode(), args.colormap),
+                    origin,
+                    zoom,
+                    frames: 0,
+                })
+            }
+            Err(e) => {
+                eprintln!("Error creating frames folder: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    export(args, &sim, export_dir.as_deref());
+    record(&mut trajectory, &sim);
+    if let Some(writer) = &mut frames {
+        writer.write(&sim);
+    }
+    while sim.steps < args.steps {
+        sim.step();
+        if args.export_every > 0 && sim.steps.is_multiple_of(args.export_every) {
+            export(args, &sim, export_dir.as_deref());
+        }
+        if sim.steps.is_multiple_of(args.trajectory_every) {
+            record(&mut trajectory, &sim);
+        }
+        if let Some(writer) = &mut frames {
+            if sim.steps.is_multiple_of(args.frames_every) {
+                writer.write(&sim);
+            }
+        }
+        if sim.steps.is_multiple_of(100) {
+            println!("Step {}/{}", sim.steps, args.steps);
+        }
+    }
+    if let Some(writer) = trajectory {
+        let frames = writer.frames;
+        match writer.finish() {
+            Ok(_) => println!("Saved trajectory with {} frames", frames),
+            Err(e) => eprintln!("Error saving trajectory: {}", e),
+        }
+    }
+    if let Some(writer) = frames {
+        println!(
+            "Rendered {} frames to {}",
+            writer.frames,
+            writer.dir.display()
+        );
+    }
+    println!(
+        "Finished {} steps in {:.2}s",
+        sim.steps,
+        started.elapsed().as_secs_f32()
+    );
+}
+
+fn export(args: &Args, sim: &Simulation, dir: Option<&Path>) {
+    let Some(dir) = dir else {
+        return;
+    };
+    for &format in &args.export_formats {
+        if let Err(e) = export_snapshot(&sim.particles, format, sim.clock(), args.seed, dir) {
+            eprintln!("Error exporting snapshot: {}", e);
+        }
+    }
+    if args.export_tree {
+        println!("Step {} tree {}", sim.steps, sim.qt.stats());
+        if let Err(e) = export_tree(&sim.qt, sim.steps, dir) {
+            eprintln!("Error exporting quadtree: {}", e);
+        }
+    }
+}
+
+fn record(trajectory: &mut Option<TrajectoryWriter>, sim: &Simulation) {
+    if let Some(writer) = trajectory {
+        if let Err(e) = writer.write_frame(&sim.particles, sim.clock()) {
+            eprintln!("Error writing trajectory frame: {}", e);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::consts::{HEIGHT, WIDTH};
+use crate::particle::ParticleSystem;
+use crate::quadtree::TreeStats;
+use crate::simulation::Simulation;
+use ggez::graphics::{self, Canvas, Color, DrawParam, Text};
+use ggez::Context;
+
+/// Energy and tree statistics cost about as much as a step, so they are refreshed only
+/// every this many frames.
+const REFRESH_FRAMES: u32 = 10;
+const HUD_WIDTH: f32 = 440.0;
+const LINE_HEIGHT: f32 = 28.0;
+
+/// Overlay with the state of the simulation and the view in the top right corner.
+pub struct Hud {
+    pub visible: bool,
+    /// Energy the drift is measured against, taken when the HUD is first shown.
+    initial_energy: Option<f32>,
+    energy: f32,
+    tree: TreeStats,
+    frames_since_refresh: u32,
+}
+
+impl Hud {
+    pub fn new(visible: bool) -> Self {
+        Self {
+            visible,
+            initial_energy: None,
+            energy: 0.0,
+            tree: TreeStats::default(),
+            frames_since_refresh: REFRESH_FRAMES,
+        }
+    }
+
+    /// Measures the energy drift from the current state again, e.g. after particles were
+    /// added by hand.
+    pub fn reset_energy(&mut self) {
+        self.initial_energy = None;
+        self.frames_since_refresh = REFRESH_FRAMES;
+    }
+
+    fn refresh(&mut self, sim: &Simulation) {
+        self.frames_since_refresh += 1;
+        if self.frames_since_refresh < REFRESH_FRAMES {
+            return;
+        }
+        self.frames_since_refresh = 0;
+        self.energy = sim.energy().total();
+        self.initial_energy.get_or_insert(self.energy);
+        self.tree = sim.qt.stats();
+    }
+
+    fn drift(&self) -> f32 {
+        match self.initial_energy {
+            Some(initial) if initial != 0.0 => (self.energy - initial) / initial.abs(),
+            _ => 0.0,
+        }
+    }
+
+    /// Draws the simulation state followed by `view_lines` describing the window.
+    pub fn draw(
+        &mut self,
+        canvas: &mut Canvas,
+        ctx: &mut Context,
+        sim: &Simulation,
+        view_lines: &[String],
+    ) {
+        if !self.visible {
+            return;
+        }
+        self.refresh(sim);
+        let mut lines = vec![
+            format!("Particles: {}", sim.particles.count),
+            format!("Step: {}", sim.steps),
+            format!("Time: {:.2} (dt {})", sim.time, sim.dt),
+            format!("Energy: {:.4e}", self.energy),
+            format!("Energy drift: {:+.3e}", self.drift()),
+            format!(
+                "Tree: {} nodes, depth {}",
+                self.tree.node_count, self.tree.max_depth
+            ),
+        ];
+        lines.extend_from_slice(view_lines);
+
+        draw_panel(canvas, ctx, [WIDTH - HUD_WIDTH - 20.0, 20.0], &lines);
+    }
+}
+
+/// Draws `lines` of text on a translucent background with its top left corner at `pos`.
+fn draw_panel(canvas: &mut Canvas, ctx: &mut Context, pos: [f32; 2], lines: &[String]) {
+    let [x, y] = pos;
+    let background = graphics::Rect::new(
+        x - 10.0,
+        y - 10.0,
+        HUD_WIDTH,
+        lines.len() as f32 * LINE_HEIGHT + 16.0,
+    );
+    if let Ok(mesh) = graphics::Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::fill(),
+        background,
+        Color::new(0.0, 0.0, 0.0, 0.6),
+    ) {
+        canvas.draw(&mesh, DrawParam::default());
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let mut text = Text::new(line.as_str());
+        text.set_scale(24.0);
+        canvas.draw(
+            &text,
+            DrawParam::default()
+                .dest([x, y + i as f32 * LINE_HEIGHT])
+                .color(Color::WHITE),
+        );
+    }
+}
+
+/// Shows the state of particle `idx` in the bottom left corner.
+pub fn draw_inspector(
+    canvas: &mut Canvas,
+    ctx: &mut Context,
+    particles: &ParticleSystem,
+    idx: usize,
+    following: bool,
+) {
+    let pos = particles.get_position(idx);
+    let vel = particles.get_velocity(idx);
+    let acc = particles.get_net_force(idx) / particles.mass[idx];
+    let mut lines = vec![
+        format!(
+            "Particle {} (species {})",
+            particles.indices[idx], particles.species[idx]
+        ),
+        format!("Mass: {:.4e}", particles.mass[idx]),
+        format!("Position: ({:.2}, {:.2})", pos.x, pos.y),
+        format!("Velocity: ({:.4}, {:.4}) |{:.4}|", vel.x, vel.y, vel.norm()),
+        format!("Acceleration: ({:.2e}, {:.2e})", acc.x, acc.y),
+    ];
+    if following {
+        lines.push("Camera following".to_string());
+    }
+    let y = HEIGHT - 20.0 - lines.len() as f32 * LINE_HEIGHT;
+    draw_panel(canvas, ctx, [30.0, y], &lines);
+}
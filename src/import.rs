@@ -0,0 +1,468 @@
+use crate::particle::ParticleSystem;
+use flate2::read::DeflateDecoder;
+use nalgebra::Vector2;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Columns every initial-condition file has to provide, in the order used for bare `.npy` files.
+const REQUIRED_COLUMNS: [&str; 6] = ["x", "y", "vx", "vy", "m", "r"];
+
+/// Loads particles from a `.csv`, `.npy` or `.npz` file, or from a folder of per-column `.npy`
+/// files as written by the exporter, and appends them to `particles`.
+///
+/// CSV files need a header naming the columns `x, y, vx, vy, m, r` (an optional `id` column is
+/// used for the particle ids). A single `.npy` file has to hold an `(N, 6)` array with the columns
+/// in that order, while `.npz` archives and folders hold one 1D array per column.
+///
+/// Returns the number of particles that were added.
+pub fn load_particles(particles: &mut ParticleSystem, path: &Path) -> io::Result<usize> {
+    let columns = if path.is_dir() {
+        read_npy_dir(path)?
+    } else {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("csv") => read_csv(&fs::read_to_string(path)?)?,
+            Some("npy") => read_npy_matrix(&fs::read(path)?)?,
+            Some("npz") => read_npz(&fs::read(path)?)?,
+            _ => {
+                return Err(invalid(format!(
+                    "Unsupported initial conditions file: {}",
+                    path.display()
+                )))
+            }
+        }
+    };
+
+    let count = validate(&columns)?;
+    let ids = columns.get("id");
+    particles.start_species();
+    for i in 0..count {
+        let col = |name: &str| columns[name][i] as f32;
+        let id = ids.map_or(i, |ids| ids[i] as usize);
+        particles.add_particle(
+            Vector2::new(col("x"), col("y")),
+            Vector2::new(col("vx"), col("vy")),
+            col("m"),
+            col("r"),
+            id,
+        );
+    }
+    Ok(count)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Checks that all required columns exist, have the same length and hold finite values.
+fn validate(columns: &HashMap<String, Vec<f64>>) -> io::Result<usize> {
+    let mut count = None;
+    for name in REQUIRED_COLUMNS.iter().chain(["id"].iter()) {
+        let Some(values) = columns.get(*name) else {
+            if *name == "id" {
+                continue;
+            }
+            return Err(invalid(format!("Missing column '{}'", name)));
+        };
+        match count {
+            None => count = Some(values.len()),
+            Some(n) if n != values.len() => {
+                return Err(invalid(format!(
+                    "Column '{}' has {} values, expected {}",
+                    name,
+                    values.len(),
+                    n
+                )))
+            }
+            _ => {}
+        }
+        if let Some(row) = values.iter().position(|v| !v.is_finite()) {
+            return Err(invalid(format!(
+                "Column '{}' has a non-finite value in row {}",
+                name, row
+            )));
+        }
+    }
+    if let Some(row) = columns["m"].iter().position(|&m| m <= 0.0) {
+        return Err(invalid(format!("Mass in row {} is not positive", row)));
+    }
+    if let Some(row) = columns["r"].iter().position(|&r| r < 0.0) {
+        return Err(invalid(format!("Radius in row {} is negative", row)));
+    }
+    Ok(count.unwrap_or(0))
+}
+
+fn column_name(header: &str) -> String {
+    let name = header.trim().to_ascii_lowercase();
+    match name.as_str() {
+        "mass" => "m".to_string(),
+        "radius" => "r".to_string(),
+        _ => name,
+    }
+}
+
+fn read_csv(text: &str) -> io::Result<HashMap<String, Vec<f64>>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    let Some((_, header)) = lines.next() else {
+        return Err(invalid("CSV file is empty".to_string()));
+    };
+    let names: Vec<String> = header.split(',').map(column_name).collect();
+    let mut columns: Vec<Vec<f64>> = vec![Vec::new(); names.len()];
+
+    for (line_idx, line) in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != names.len() {
+            return Err(invalid(format!(
+                "Line {} has {} fields, expected {}",
+                line_idx + 1,
+                fields.len(),
+                names.len()
+            )));
+        }
+        for (column, field) in columns.iter_mut().zip(fields) {
+            let value = field.trim().parse::<f64>().map_err(|_| {
+                invalid(format!("Line {}: invalid number '{}'", line_idx + 1, field))
+            })?;
+            column.push(value);
+        }
+    }
+    Ok(names.into_iter().zip(columns).collect())
+}
+
+struct NpyArray {
+    shape: Vec<usize>,
+    fortran_order: bool,
+    data: Vec<f64>,
+}
+
+fn parse_npy(bytes: &[u8]) -> io::Result<NpyArray> {
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err(invalid("Not a .npy file".to_string()));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        version => return Err(invalid(format!("Unsupported .npy version {}", version))),
+    };
+    let data_start = header_start + header_len;
+    let header = bytes
+        .get(header_start..data_start)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(|| invalid("Malformed .npy header".to_string()))?;
+
+    let descr = header_value(header, "descr")
+        .map(|v| v.trim_matches(|c| c == '\'' || c == '"'))
+        .ok_or_else(|| invalid("Missing dtype in .npy header".to_string()))?;
+    let fortran_order = header_value(header, "fortran_order") == Some("True");
+    let shape: Vec<usize> = header_value(header, "shape")
+        .ok_or_else(|| invalid("Missing shape in .npy header".to_string()))?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid("Malformed shape in .npy header".to_string()))?;
+
+    let (size, decode): (usize, fn(&[u8]) -> f64) = match descr {
+        "<f4" => (4, |b| f32::from_le_bytes(b.try_into().unwrap()) as f64),
+        "<f8" => (8, |b| f64::from_le_bytes(b.try_into().unwrap())),
+        "<i4" => (4, |b| i32::from_le_bytes(b.try_into().unwrap()) as f64),
+        "<i8" => (8, |b| i64::from_le_bytes(b.try_into().unwrap()) as f64),
+        "<u4" => (4, |b| u32::from_le_bytes(b.try_into().unwrap()) as f64),
+        "<u8" => (8, |b| u64::from_le_bytes(b.try_into().unwrap()) as f64),
+        _ => return Err(invalid(format!("Unsupported .npy dtype {}", descr))),
+    };
+    // The header is untrusted, so a huge shape must not overflow the length computations.
+    let data_end = shape
+        .iter()
+        .try_fold(size, |bytes, &dim| bytes.checked_mul(dim))
+        .and_then(|len| data_start.checked_add(len))
+        .ok_or_else(|| invalid("Shape in .npy header is too large".to_string()))?;
+    let payload = bytes
+        .get(data_start..data_end)
+        .ok_or_else(|| invalid("Truncated .npy data".to_string()))?;
+    let data = payload.chunks_exact(size).map(decode).collect();
+
+    Ok(NpyArray {
+        shape,
+        fortran_order,
+        data,
+    })
+}
+
+/// Returns the raw text of `key` in a `.npy` header dict such as `{'descr': '<f4', ...}`.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(',')?
+    };
+    Some(rest[..end].trim())
+}
+
+fn read_npy_matrix(bytes: &[u8]) -> io::Result<HashMap<String, Vec<f64>>> {
+    let array = parse_npy(bytes)?;
+    let [rows, cols] = array.shape[..] else {
+        return Err(invalid(format!(
+            "Expected a 2D .npy array, got shape {:?}",
+            array.shape
+        )));
+    };
+    if cols != REQUIRED_COLUMNS.len() {
+        return Err(invalid(format!(
+            "Expected {} columns (x, y, vx, vy, m, r), got {}",
+            REQUIRED_COLUMNS.len(),
+            cols
+        )));
+    }
+    let mut columns = HashMap::new();
+    for (c, name) in REQUIRED_COLUMNS.iter().enumerate() {
+        let values = (0..rows)
+            .map(|r| {
+                if array.fortran_order {
+                    array.data[c * rows + r]
+                } else {
+                    array.data[r * cols + c]
+                }
+            })
+            .collect();
+        columns.insert(name.to_string(), values);
+    }
+    Ok(columns)
+}
+
+fn insert_column(
+    columns: &mut HashMap<String, Vec<f64>>,
+    name: &str,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let array = parse_npy(bytes)?;
+    if array.shape.len() != 1 {
+        return Err(invalid(format!(
+            "Column '{}' is not a 1D array (shape {:?})",
+            name, array.shape
+        )));
+    }
+    columns.insert(column_name(name), array.data);
+    Ok(())
+}
+
+fn read_npy_dir(dir: &Path) -> io::Result<HashMap<String, Vec<f64>>> {
+    let mut columns = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(std::ffi::OsStr::new("npy")) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            insert_column(&mut columns, name, &fs::read(&path)?)?;
+        }
+    }
+    Ok(columns)
+}
+
+fn read_npz(bytes: &[u8]) -> io::Result<HashMap<String, Vec<f64>>> {
+    let mut columns = HashMap::new();
+    for (name, data) in read_zip(bytes)? {
+        if let Some(name) = name.strip_suffix(".npy") {
+            insert_column(&mut columns, name, &data)?;
+        }
+    }
+    Ok(columns)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> io::Result<u16> {
+    bytes
+        .get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("Truncated zip archive".to_string()))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> io::Result<u32> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("Truncated zip archive".to_string()))
+}
+
+/// Minimal zip reader for `.npz` archives, supporting stored and deflated entries as written by
+/// `numpy.savez` and `numpy.savez_compressed`.
+fn read_zip(bytes: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&i| bytes[i..].starts_with(&0x0605_4b50u32.to_le_bytes()))
+        .ok_or_else(|| invalid("Not a zip archive".to_string()))?;
+    let entry_count = read_u16(bytes, end + 10)? as usize;
+    let mut at = read_u32(bytes, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        if read_u32(bytes, at)? != 0x0201_4b50 {
+            return Err(invalid("Corrupt zip central directory".to_string()));
+        }
+        let method = read_u16(bytes, at + 10)?;
+        let mut compressed_size = read_u32(bytes, at + 20)? as u64;
+        let mut size = read_u32(bytes, at + 24)? as u64;
+        let name_len = read_u16(bytes, at + 28)? as usize;
+        let extra_len = read_u16(bytes, at + 30)? as usize;
+        let comment_len = read_u16(bytes, at + 32)? as usize;
+        let mut local_offset = read_u32(bytes, at + 42)? as u64;
+        let name_bytes = bytes
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid("Truncated zip archive".to_string()))?;
+        let name = String::from_utf8_lossy(name_bytes).into_owned();
+
+        // Zip64 extra field: only the values saturated in the header are present, in this order.
+        let mut extra = at + 46 + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let id = read_u16(bytes, extra)?;
+            let len = read_u16(bytes, extra + 2)? as usize;
+            if id == 0x0001 {
+                let mut field = extra + 4;
+                for value in [&mut size, &mut compressed_size, &mut local_offset] {
+                    if *value == u32::MAX as u64 {
+                        *value = u64::from(read_u32(bytes, field)?)
+                            | (u64::from(read_u32(bytes, field + 4)?) << 32);
+                        field += 8;
+                    }
+                }
+            }
+            extra += 4 + len;
+        }
+        at = extra_end + comment_len;
+
+        let local = local_offset as usize;
+        if read_u32(bytes, local)? != 0x0403_4b50 {
+            return Err(invalid("Corrupt zip local header".to_string()));
+        }
+        let data_start = local
+            + 30
+            + read_u16(bytes, local + 26)? as usize
+            + read_u16(bytes, local + 28)? as usize;
+        let raw = bytes
+            .get(data_start..data_start + compressed_size as usize)
+            .ok_or_else(|| invalid("Truncated zip archive".to_string()))?;
+        let data = match method {
+            0 => raw.to_vec(),
+            8 => {
+                let mut data = Vec::with_capacity(size as usize);
+                DeflateDecoder::new(raw).read_to_end(&mut data)?;
+                data
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "Unsupported zip compression method {} for {}",
+                    method, name
+                )))
+            }
+        };
+        entries.push((name, data));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn npy(descr: &str, shape: &str, payload: &[u8]) -> Vec<u8> {
+        let dict = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}\n",
+            descr, shape
+        );
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn csv_error(text: &str) -> String {
+        let columns = read_csv(text).unwrap();
+        validate(&columns).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn csv_with_all_columns_is_valid() {
+        let columns =
+            read_csv("# seed=1\nx,y,vx,vy,mass,radius\n1,2,3,4,5,0\n6,7,8,9,10,1\n").unwrap();
+        assert_eq!(validate(&columns).unwrap(), 2);
+        assert_eq!(columns["m"], vec![5.0, 10.0]);
+    }
+
+    #[test]
+    fn missing_column_is_rejected() {
+        assert!(csv_error("x,y,vx,vy,m\n1,2,3,4,5\n").contains("Missing column 'r'"));
+    }
+
+    #[test]
+    fn column_length_mismatch_is_rejected() {
+        let mut columns = read_csv("x,y,vx,vy,m,r\n1,2,3,4,5,0\n").unwrap();
+        columns.get_mut("vy").unwrap().push(1.0);
+        let err = validate(&columns).unwrap_err().to_string();
+        assert!(
+            err.contains("Column 'vy' has 2 values, expected 1"),
+            "{}",
+            err
+        );
+        assert!(read_csv("x,y,vx,vy,m,r\n1,2,3,4,5\n").is_err());
+    }
+
+    #[test]
+    fn non_finite_values_are_rejected() {
+        assert!(csv_error("x,y,vx,vy,m,r\nNaN,2,3,4,5,0\n").contains("non-finite"));
+        assert!(csv_error("x,y,vx,vy,m,r\n1,inf,3,4,5,0\n").contains("non-finite"));
+    }
+
+    #[test]
+    fn non_positive_mass_and_negative_radius_are_rejected() {
+        assert!(csv_error("x,y,vx,vy,m,r\n1,2,3,4,5,0\n1,2,3,4,0,0\n").contains("row 1"));
+        assert!(csv_error("x,y,vx,vy,m,r\n1,2,3,4,-5,0\n").contains("not positive"));
+        assert!(csv_error("x,y,vx,vy,m,r\n1,2,3,4,5,-1\n").contains("negative"));
+    }
+
+    #[test]
+    fn npy_matrix_is_read_row_major() {
+        let payload: Vec<u8> = (0..12).flat_map(|v| (v as f32).to_le_bytes()).collect();
+        let columns = read_npy_matrix(&npy("<f4", "(2, 6)", &payload)).unwrap();
+        assert_eq!(columns["x"], vec![0.0, 6.0]);
+        assert_eq!(columns["r"], vec![5.0, 11.0]);
+    }
+
+    #[test]
+    fn bad_npy_headers_are_errors() {
+        let kind = |bytes: &[u8]| parse_npy(bytes).err().map(|e| e.kind());
+        let invalid_data = Some(io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"not numpy at all"), invalid_data);
+        assert_eq!(kind(&npy("<f4", "(3,)", &[0; 8])), invalid_data);
+        assert_eq!(kind(&npy("<c16", "(1,)", &[0; 16])), invalid_data);
+        assert_eq!(kind(&npy("<f4", "(x,)", &[])), invalid_data);
+        // Shapes whose byte size overflows usize.
+        let huge = format!("({}, {})", usize::MAX / 2, 3);
+        assert_eq!(kind(&npy("<f8", &huge, &[])), invalid_data);
+        assert_eq!(
+            kind(&npy("<f8", &format!("({},)", usize::MAX), &[])),
+            invalid_data
+        );
+        // The header length points past the end of the file.
+        let mut truncated = npy("<f4", "(1,)", &[0; 4]);
+        truncated[8] = 0xff;
+        truncated.truncate(20);
+        assert_eq!(kind(&truncated), invalid_data);
+    }
+}
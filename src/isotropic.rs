@@ -0,0 +1,310 @@
+use crate::consts::{G, SOFTENING};
+use crate::particle::ParticleSystem;
+use nalgebra::Vector2;
+use rand::Rng;
+use std::f64::consts::PI;
+
+const TABLE_SIZE: usize = 4096;
+
+/// A radially symmetric, razor-thin mass distribution that can be sampled in equilibrium.
+///
+/// Both functions have to be absolute (mass per area and energy per mass) and
+/// `relative_potential` is `-Φ` in the plane, so it must be positive and decrease outwards.
+pub trait IsotropicProfile {
+    fn surface_density(&self, r: f64) -> f64;
+    fn relative_potential(&self, r: f64) -> f64;
+    /// Particles are only sampled inside this radius.
+    fn truncation_radius(&self) -> f64;
+}
+
+/// The planar Plummer model: a Kuzmin disk, whose in-plane potential is exactly the Plummer
+/// potential `-GM / sqrt(r² + a²)`.
+pub struct Plummer {
+    pub mass: f64,
+    pub scale_radius: f64,
+    pub truncation: f64,
+}
+
+impl Plummer {
+    pub fn new(mass: f32, scale_radius: f32) -> Self {
+        Self {
+            mass: mass as f64,
+            scale_radius: scale_radius as f64,
+            // Holds 95% of the mass.
+            truncation: 20.0 * scale_radius as f64,
+        }
+    }
+}
+
+impl IsotropicProfile for Plummer {
+    fn surface_density(&self, r: f64) -> f64 {
+        let a = self.scale_radius;
+        self.mass * a / (2.0 * PI * (r * r + a * a).powf(1.5))
+    }
+
+    fn relative_potential(&self, r: f64) -> f64 {
+        // Plummer softening acts like looking at the disk from height SOFTENING, which for a
+        // Kuzmin disk is the same as widening its scale radius.
+        let a = self.scale_radius + SOFTENING as f64;
+        G as f64 * self.mass / (r * r + a * a).sqrt()
+    }
+
+    fn truncation_radius(&self) -> f64 {
+        self.truncation
+    }
+}
+
+/// Radii at which the profiles are tabulated, out to the truncation radius.
+fn table_radii(profile: &impl IsotropicProfile) -> Vec<f64> {
+    let r_max = profile.truncation_radius();
+    (0..TABLE_SIZE)
+        .map(|i| r_max * i as f64 / (TABLE_SIZE - 1) as f64)
+        .collect()
+}
+
+/// Mass inside each radius, integrated with the trapezoidal rule.
+fn cumulative_mass(radius: &[f64], density: &[f64]) -> Vec<f64> {
+    let mut mass = vec![0.0; radius.len()];
+    for i in 1..radius.len() {
+        let dr = radius[i] - radius[i - 1];
+        let ring = PI * (radius[i] * density[i] + radius[i - 1] * density[i - 1]) * dr;
+        mass[i] = mass[i - 1] + ring;
+    }
+    mass
+}
+
+/// Mass that [`create_isotropic`] shares out among the particles of `profile`, the profile's
+/// mass inside its truncation radius.
+pub fn sampled_mass(profile: &impl IsotropicProfile) -> f64 {
+    let radius = table_radii(profile);
+    let density: Vec<f64> = radius.iter().map(|&r| profile.surface_density(r)).collect();
+    cumulative_mass(&radius, &density)[TABLE_SIZE - 1]
+}
+
+/// Tabulated radius, relative potential and distribution function of a profile.
+struct Tables {
+    radius: Vec<f64>,
+    cumulative_mass: Vec<f64>,
+    potential: Vec<f64>,
+    /// `f(ε)` at `ε = potential[i]`, as the isotropic 2D Eddington inversion
+    /// `f(ε) = 1 / (2π) dΣ/dψ`.
+    df: Vec<f64>,
+    /// Running maximum of `df` from the outermost (least bound) entry inwards.
+    df_max: Vec<f64>,
+}
+
+impl Tables {
+    fn new(profile: &impl IsotropicProfile) -> Self {
+        let radius = table_radii(profile);
+        let density: Vec<f64> = radius.iter().map(|&r| profile.surface_density(r)).collect();
+        let potential: Vec<f64> = radius
+            .iter()
+            .map(|&r| profile.relative_potential(r))
+            .collect();
+
+        let cumulative_mass = cumulative_mass(&radius, &density);
+
+        let df: Vec<f64> = (0..TABLE_SIZE)
+            .map(|i| {
+                let (lo, hi) = (i.saturating_sub(1), (i + 1).min(TABLE_SIZE - 1));
+                let d_density = density[hi] - density[lo];
+                let d_potential = potential[hi] - potential[lo];
+                if d_potential.abs() < f64::EPSILON {
+                    return 0.0;
+                }
+                (d_density / d_potential / (2.0 * PI)).max(0.0)
+            })
+            .collect();
+        let mut df_max = df.clone();
+        for i in (0..TABLE_SIZE - 1).rev() {
+            df_max[i] = df_max[i].max(df_max[i + 1]);
+        }
+
+        Self {
+            radius,
+            cumulative_mass,
+            potential,
+            df,
+            df_max,
+        }
+    }
+
+    fn total_mass(&self) -> f64 {
+        self.cumulative_mass[TABLE_SIZE - 1]
+    }
+
+    /// Radius holding the mass fraction `u`.
+    fn radius_for_mass(&self, u: f64) -> f64 {
+        let target = u * self.total_mass();
+        let i = self
+            .cumulative_mass
+            .partition_point(|&m| m < target)
+            .clamp(1, TABLE_SIZE - 1);
+        let (m0, m1) = (self.cumulative_mass[i - 1], self.cumulative_mass[i]);
+        let t = if m1 > m0 {
+            (target - m0) / (m1 - m0)
+        } else {
+            0.0
+        };
+        self.radius[i - 1] + t * (self.radius[i] - self.radius[i - 1])
+    }
+
+    /// Index of the table entry whose potential is closest to `psi` from below.
+    fn index_for_potential(&self, psi: f64) -> usize {
+        // The potential decreases with the index.
+        self.potential
+            .partition_point(|&p| p > psi)
+            .min(TABLE_SIZE - 1)
+    }
+
+    fn sample_speed(&self, rng: &mut impl Rng, r: f64) -> f64 {
+        let i = ((r / self.radius[TABLE_SIZE - 1]) * (TABLE_SIZE - 1) as f64) as usize;
+        let i = i.min(TABLE_SIZE - 1);
+        let psi = self.potential[i];
+        let f_max = self.df_max[i];
+        if f_max <= 0.0 {
+            return 0.0;
+        }
+        // p(v) dv ∝ v f(ψ - v²/2) dv, which is uniform in v² times f.
+        loop {
+            let v_sq = rng.gen_range(0.0..2.0 * psi);
+            let energy = psi - 0.5 * v_sq;
+            let f = self.df[self.index_for_potential(energy)];
+            if rng.gen_range(0.0..f_max) < f {
+                return v_sq.sqrt();
+            }
+        }
+    }
+}
+
+/// Samples an isotropic equilibrium model of `profile` from its distribution function, so the
+/// cluster starts in virial equilibrium. Each particle gets an equal share of the profile's mass
+/// inside its truncation radius.
+pub fn create_isotropic(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    center: Vector2<f32>,
+    initial_vel: Vector2<f32>,
+    profile: &impl IsotropicProfile,
+    particles_amount: i32,
+) {
+    if particles_amount <= 0 {
+        return;
+    }
+    let tables = Tables::new(profile);
+    particles.start_species();
+    let particle_mass = (tables.total_mass() / particles_amount as f64) as f32;
+
+    let mut positions = Vec::with_capacity(particles_amount as usize);
+    let mut velocities = Vec::with_capacity(particles_amount as usize);
+    for _ in 0..particles_amount {
+        let r = tables.radius_for_mass(rng.gen_range(0.0..1.0));
+        let speed = tables.sample_speed(rng, r);
+        let pos_angle = rng.gen_range(0.0..2.0 * PI);
+        let vel_angle = rng.gen_range(0.0..2.0 * PI);
+        positions.push(Vector2::new(r * pos_angle.cos(), r * pos_angle.sin()).cast::<f32>());
+        velocities
+            .push(Vector2::new(speed * vel_angle.cos(), speed * vel_angle.sin()).cast::<f32>());
+    }
+
+    // Remove the sampling noise in the center of mass position and velocity.
+    let n = particles_amount as f32;
+    let mean_pos = positions.iter().sum::<Vector2<f32>>() / n;
+    let mean_vel = velocities.iter().sum::<Vector2<f32>>() / n;
+    for (i, (pos, vel)) in positions.iter().zip(&velocities).enumerate() {
+        particles.add_particle(
+            pos - mean_pos + center,
+            vel - mean_vel + initial_vel,
+            particle_mass,
+            0.001,
+            i,
+        );
+    }
+}
+
+/// Planar Plummer cluster in virial equilibrium, see [`Plummer`]. The model is truncated at
+/// 20 scale radii, so the particles carry 95% of `total_mass`.
+pub fn create_plummer(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    center: Vector2<f32>,
+    initial_vel: Vector2<f32>,
+    scale_radius: f32,
+    total_mass: f32,
+    particles_amount: i32,
+) {
+    create_isotropic(
+        particles,
+        rng,
+        center,
+        initial_vel,
+        &Plummer::new(total_mass, scale_radius),
+        particles_amount,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn plummer_matches_the_analytic_mass_and_dispersion() {
+        let (mass, a) = (1000.0f32, 10.0f32);
+        let amount = 20000;
+        let mut particles = ParticleSystem::new();
+        let mut rng = StdRng::seed_from_u64(3);
+        create_plummer(
+            &mut particles,
+            &mut rng,
+            Vector2::zeros(),
+            Vector2::zeros(),
+            a,
+            mass,
+            amount,
+        );
+        assert_eq!(particles.count, amount as usize);
+
+        // A Kuzmin disk holds M (1 - a / sqrt(R² + a²)) inside R, 95% at the truncation.
+        let profile = Plummer::new(mass, a);
+        let (a, r_max) = (a as f64, profile.truncation_radius());
+        let expected_mass = mass as f64 * (1.0 - a / (r_max * r_max + a * a).sqrt());
+        let total: f64 = particles.mass.iter().map(|&m| m as f64).sum();
+        assert!(
+            (total / expected_mass - 1.0).abs() < 1e-3,
+            "{} vs {}",
+            total,
+            expected_mass
+        );
+
+        // Isotropic Jeans equation: Σ σ² = ∫_r^∞ Σ dψ/dr' dr' per velocity component, which
+        // is tabulated from the outside in.
+        let steps = 20000;
+        let dr = 2.0 * r_max / steps as f64;
+        let mut jeans = vec![0.0; steps + 1];
+        for i in (0..steps).rev() {
+            let r = (i as f64 + 0.5) * dr;
+            let slope = (profile.relative_potential(r) - profile.relative_potential(r + dr)) / dr;
+            jeans[i] = jeans[i + 1] + profile.surface_density(r) * slope * dr;
+        }
+        let mean_speed_sq = |r: f64| 2.0 * jeans[(r / dr) as usize] / profile.surface_density(r);
+
+        let (mut sampled, mut predicted, mut n) = (0.0, 0.0, 0);
+        for i in 0..particles.count {
+            let r = particles.get_position(i).norm() as f64;
+            if r < a {
+                sampled += particles.get_velocity_norm(i).powi(2) as f64;
+                predicted += mean_speed_sq(r);
+                n += 1;
+            }
+        }
+        assert!(n > 4000);
+        let ratio = sampled / predicted;
+        assert!(
+            (ratio - 1.0).abs() < 0.05,
+            "<v²> is {} of the Jeans value",
+            ratio
+        );
+    }
+}
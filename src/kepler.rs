@@ -0,0 +1,314 @@
+use crate::consts::G;
+use crate::galaxy::Spin;
+use crate::particle::ParticleSystem;
+use nalgebra::Vector2;
+use std::f64::consts::PI;
+
+/// Elliptic orbit of a body around its parent.
+///
+/// Angles are in radians and measured in the direction of motion, starting from the +x axis.
+#[derive(Clone, Debug)]
+pub struct Orbit {
+    pub semi_major_axis: f32,
+    /// Must be in `0..1`.
+    pub eccentricity: f32,
+    pub argument_of_periapsis: f32,
+    pub mean_anomaly: f32,
+    pub spin: Spin,
+}
+
+impl Orbit {
+    pub fn circular(radius: f32, phase: f32) -> Self {
+        Self {
+            semi_major_axis: radius,
+            eccentricity: 0.0,
+            argument_of_periapsis: 0.0,
+            mean_anomaly: phase,
+            spin: Spin::CounterClockwise,
+        }
+    }
+}
+
+/// A body and the bodies orbiting it, such as a planet with its moons.
+#[derive(Clone, Debug)]
+pub struct Body {
+    pub mass: f32,
+    pub radius: f32,
+    /// Orbit around the parent, ignored for the root body of a system.
+    pub orbit: Orbit,
+    pub satellites: Vec<Body>,
+}
+
+impl Body {
+    pub fn new(mass: f32, radius: f32, orbit: Orbit) -> Self {
+        Self {
+            mass,
+            radius,
+            orbit,
+            satellites: Vec::new(),
+        }
+    }
+
+    pub fn with_satellite(mut self, satellite: Body) -> Self {
+        self.satellites.push(satellite);
+        self
+    }
+
+    /// Mass of the body together with everything orbiting it.
+    fn system_mass(&self) -> f32 {
+        self.mass + self.satellites.iter().map(Body::system_mass).sum::<f32>()
+    }
+}
+
+/// Solves Kepler's equation `M = E - e sin E` for the eccentric anomaly `E`.
+fn eccentric_anomaly(mean_anomaly: f64, eccentricity: f64) -> f64 {
+    let m = mean_anomaly.rem_euclid(2.0 * PI);
+    // Starting at π keeps Newton's method from overshooting on very eccentric orbits.
+    let mut e_anomaly = if eccentricity > 0.8 { PI } else { m };
+    for _ in 0..50 {
+        let f = e_anomaly - eccentricity * e_anomaly.sin() - m;
+        let step = f / (1.0 - eccentricity * e_anomaly.cos());
+        e_anomaly -= step;
+        if step.abs() < 1e-12 {
+            break;
+        }
+    }
+    e_anomaly
+}
+
+/// Position and velocity of the orbiting body relative to the one it orbits, where
+/// `total_mass` is the mass of both.
+fn relative_state(orbit: &Orbit, total_mass: f32) -> (Vector2<f32>, Vector2<f32>) {
+    let a = orbit.semi_major_axis as f64;
+    let e = orbit.eccentricity as f64;
+    assert!(
+        (0.0..1.0).contains(&e),
+        "Orbit eccentricity must be in 0..1, got {}",
+        e
+    );
+    let e_anomaly = eccentric_anomaly(orbit.mean_anomaly as f64, e);
+    let (sin_e, cos_e) = e_anomaly.sin_cos();
+    let mean_motion = (G as f64 * total_mass as f64 / (a * a * a)).sqrt();
+    let minor = (1.0 - e * e).sqrt();
+
+    // Perifocal frame, periapsis along +x and moving towards +y.
+    let pos = Vector2::new(a * (cos_e - e), a * minor * sin_e);
+    let vel = Vector2::new(-sin_e, minor * cos_e) * (a * mean_motion / (1.0 - e * cos_e));
+
+    let (sin_w, cos_w) = (orbit.argument_of_periapsis as f64).sin_cos();
+    // Screen y points down, so counter-clockwise on screen is clockwise in these coordinates.
+    let flip = -orbit.spin.sign() as f64;
+    let rotate = |v: Vector2<f64>| {
+        Vector2::new(
+            v.x * cos_w - v.y * sin_w,
+            flip * (v.x * sin_w + v.y * cos_w),
+        )
+        .cast::<f32>()
+    };
+    (rotate(pos), rotate(vel))
+}
+
+/// Positions and velocities of `body` and all its satellites, relative to the system's
+/// barycenter. The bodies are listed depth first, parents before their satellites.
+fn system_states(body: &Body, states: &mut Vec<(Vector2<f32>, Vector2<f32>, f32, f32)>) {
+    let start = states.len();
+    states.push((Vector2::zeros(), Vector2::zeros(), body.mass, body.radius));
+    for satellite in &body.satellites {
+        // Each satellite system orbits the parent body, and the sum of both is the two-body mass.
+        let (pos, vel) = relative_state(&satellite.orbit, body.mass + satellite.system_mass());
+        let sub_start = states.len();
+        system_states(satellite, states);
+        for state in &mut states[sub_start..] {
+            state.0 += pos;
+            state.1 += vel;
+        }
+    }
+
+    // Move the barycenter to the origin.
+    let total_mass = body.system_mass();
+    let (mut com_pos, mut com_vel) = (Vector2::zeros(), Vector2::zeros());
+    for (pos, vel, mass, _) in &states[start..] {
+        com_pos += pos * *mass;
+        com_vel += vel * *mass;
+    }
+    for state in &mut states[start..] {
+        state.0 -= com_pos / total_mass;
+        state.1 -= com_vel / total_mass;
+    }
+}
+
+/// Appends `root` and everything orbiting it, with the barycenter of the whole system at
+/// `center` moving with `initial_vel`.
+///
+/// The orbits are exact two-body orbits of each satellite system around its parent, so moons
+/// should stay well inside their planet's Hill radius. Orbits closer than a few `SOFTENING`
+/// lengths are slower in the simulation than these elements suggest.
+pub fn create_kepler_system(
+    particles: &mut ParticleSystem,
+    center: Vector2<f32>,
+    initial_vel: Vector2<f32>,
+    root: &Body,
+) {
+    let mut states = Vec::new();
+    system_states(root, &mut states);
+    particles.start_species();
+    for (i, (pos, vel, mass, radius)) in states.into_iter().enumerate() {
+        particles.add_particle(pos + center, vel + initial_vel, mass, radius, i);
+    }
+}
+
+/// Appends `satellites` on orbits around the existing particle `host`, which acts as their
+/// primary with its current position, velocity and mass. The host itself is left as it is.
+#[allow(dead_code)]
+pub fn create_kepler_orbiters(particles: &mut ParticleSystem, host: usize, satellites: &[Body]) {
+    let root = Body {
+        mass: particles.mass[host],
+        radius: particles.radius[host],
+        orbit: Orbit::circular(0.0, 0.0),
+        satellites: satellites.to_vec(),
+    };
+    let mut states = Vec::new();
+    system_states(&root, &mut states);
+    // The states are relative to the barycenter, make them relative to the host instead.
+    let (root_pos, root_vel, _, _) = states[0];
+    let host_pos = particles.get_position(host);
+    let host_vel = particles.get_velocity(host);
+    let first_id = particles.next_id();
+    particles.start_species();
+    for (i, (pos, vel, mass, radius)) in states.into_iter().skip(1).enumerate() {
+        particles.add_particle(
+            pos - root_pos + host_pos,
+            vel - root_vel + host_vel,
+            mass,
+            radius,
+            first_id + i,
+        );
+    }
+}
+
+/// A star with three planets, the outer two with a moon each.
+pub fn create_planetary_system(
+    particles: &mut ParticleSystem,
+    center: Vector2<f32>,
+    initial_vel: Vector2<f32>,
+) {
+    let star = Body::new(100.0, 4.0, Orbit::circular(0.0, 0.0))
+        .with_satellite(Body::new(0.2, 1.0, Orbit::circular(45.0, 0.0)))
+        .with_satellite(
+            Body::new(
+                2.0,
+                2.0,
+                Orbit {
+                    semi_major_axis: 110.0,
+                    eccentricity: 0.1,
+                    argument_of_periapsis: 0.5,
+                    mean_anomaly: 2.0,
+                    spin: Spin::CounterClockwise,
+                },
+            )
+            .with_satellite(Body::new(0.01, 0.5, Orbit::circular(8.0, 1.0))),
+        )
+        .with_satellite(
+            Body::new(
+                1.0,
+                1.5,
+                Orbit {
+                    semi_major_axis: 200.0,
+                    eccentricity: 0.15,
+                    argument_of_periapsis: 3.0,
+                    mean_anomaly: 4.0,
+                    spin: Spin::CounterClockwise,
+                },
+            )
+            .with_satellite(Body::new(0.01, 0.5, Orbit::circular(8.0, 4.0))),
+        );
+    create_kepler_system(particles, center, initial_vel, &star);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kepler_residual(e_anomaly: f64, mean_anomaly: f64, eccentricity: f64) -> f64 {
+        let m = mean_anomaly.rem_euclid(2.0 * PI);
+        e_anomaly - eccentricity * e_anomaly.sin() - m
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= 1e-4 * expected.abs(),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn circular_eccentric_anomaly_is_the_mean_anomaly() {
+        for m in [0.0, 0.5, 2.0, PI, 5.0] {
+            assert!((eccentric_anomaly(m, 0.0) - m).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn eccentric_anomaly_converges_close_to_parabolic() {
+        for e in [0.9, 0.99, 0.999, 0.999_999] {
+            for i in 0..64 {
+                let m = i as f64 * 2.0 * PI / 64.0;
+                let e_anomaly = eccentric_anomaly(m, e);
+                assert!(
+                    kepler_residual(e_anomaly, m, e).abs() < 1e-9,
+                    "e = {}, M = {}",
+                    e,
+                    m
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn periapsis_and_apoapsis_distance_and_speed() {
+        let (a, e, mass) = (100.0f32, 0.6f32, 50.0f32);
+        let gm = G * mass;
+        let mut orbit = Orbit {
+            semi_major_axis: a,
+            eccentricity: e,
+            argument_of_periapsis: 1.0,
+            mean_anomaly: 0.0,
+            spin: Spin::CounterClockwise,
+        };
+        let (pos, vel) = relative_state(&orbit, mass);
+        assert_close(pos.norm(), a * (1.0 - e));
+        assert_close(vel.norm(), (gm * (1.0 + e) / (a * (1.0 - e))).sqrt());
+        assert!(pos.dot(&vel).abs() < 1e-4);
+
+        orbit.mean_anomaly = std::f32::consts::PI;
+        let (pos, vel) = relative_state(&orbit, mass);
+        assert_close(pos.norm(), a * (1.0 + e));
+        assert_close(vel.norm(), (gm * (1.0 - e) / (a * (1.0 + e))).sqrt());
+    }
+
+    #[test]
+    fn orbiters_circle_an_existing_host() {
+        let mut particles = ParticleSystem::new();
+        particles.add_particle(Vector2::new(-50.0, 0.0), Vector2::zeros(), 1.0, 1.0, 4);
+        particles.add_particle(
+            Vector2::new(10.0, 20.0),
+            Vector2::new(0.3, -0.1),
+            80.0,
+            2.0,
+            9,
+        );
+        let moon = Body::new(0.5, 0.5, Orbit::circular(5.0, 1.0));
+        create_kepler_orbiters(&mut particles, 1, &[moon]);
+
+        assert_eq!(particles.count, 3);
+        assert_eq!(particles.get_position(1), Vector2::new(10.0, 20.0));
+        assert_eq!(particles.indices[2], 10);
+        assert_eq!(particles.mass[2], 0.5);
+        let offset = particles.get_position(2) - particles.get_position(1);
+        let rel_vel = particles.get_velocity(2) - particles.get_velocity(1);
+        assert_close(offset.norm(), 5.0);
+        assert_close(rel_vel.norm(), (G * 80.5 / 5.0).sqrt());
+    }
+}
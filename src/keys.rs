@@ -0,0 +1,508 @@
+use ggez::input::keyboard::KeyCode;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Something the keyboard can do. Each action is named in key binding files by its
+/// `name()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Record,
+    SaveVideo,
+    Export,
+    DumpTree,
+    Trajectory,
+    ColorMode,
+    Colormap,
+    Legend,
+    DensityView,
+    Smoothing,
+    Trails,
+    Bloom,
+    Hud,
+    TreeBounds,
+    BoundsColoring,
+    ShallowerTree,
+    DeeperTree,
+    CameraLock,
+    /// Pauses the simulation or the replay.
+    Pause,
+    Step,
+    StepBatch,
+    Faster,
+    Slower,
+    /// Replay only.
+    StepBack,
+    Reverse,
+    JumpToStart,
+    JumpToEnd,
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    ZoomIn,
+    ZoomOut,
+    ResetView,
+    FitView,
+    DeleteSelection,
+    Heavier,
+    Lighter,
+    KickUp,
+    KickDown,
+    KickLeft,
+    KickRight,
+    SaveGroup,
+    ClearSelection,
+}
+
+impl Action {
+    pub const ALL: [Action; 44] = [
+        Action::Record,
+        Action::SaveVideo,
+        Action::Export,
+        Action::DumpTree,
+        Action::Trajectory,
+        Action::ColorMode,
+        Action::Colormap,
+        Action::Legend,
+        Action::DensityView,
+        Action::Smoothing,
+        Action::Trails,
+        Action::Bloom,
+        Action::Hud,
+        Action::TreeBounds,
+        Action::BoundsColoring,
+        Action::ShallowerTree,
+        Action::DeeperTree,
+        Action::CameraLock,
+        Action::Pause,
+        Action::Step,
+        Action::StepBatch,
+        Action::Faster,
+        Action::Slower,
+        Action::StepBack,
+        Action::Reverse,
+        Action::JumpToStart,
+        Action::JumpToEnd,
+        Action::PanUp,
+        Action::PanDown,
+        Action::PanLeft,
+        Action::PanRight,
+        Action::ZoomIn,
+        Action::ZoomOut,
+        Action::ResetView,
+        Action::FitView,
+        Action::DeleteSelection,
+        Action::Heavier,
+        Action::Lighter,
+        Action::KickUp,
+        Action::KickDown,
+        Action::KickLeft,
+        Action::KickRight,
+        Action::SaveGroup,
+        Action::ClearSelection,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Record => "record",
+            Action::SaveVideo => "save_video",
+            Action::Export => "export",
+            Action::DumpTree => "dump_tree",
+            Action::Trajectory => "trajectory",
+            Action::ColorMode => "color_mode",
+            Action::Colormap => "colormap",
+            Action::Legend => "legend",
+            Action::DensityView => "density_view",
+            Action::Smoothing => "smoothing",
+            Action::Trails => "trails",
+            Action::Bloom => "bloom",
+            Action::Hud => "hud",
+            Action::TreeBounds => "tree_bounds",
+            Action::BoundsColoring => "bounds_coloring",
+            Action::ShallowerTree => "shallower_tree",
+            Action::DeeperTree => "deeper_tree",
+            Action::CameraLock => "camera_lock",
+            Action::Pause => "pause",
+            Action::Step => "step",
+            Action::StepBatch => "step_batch",
+            Action::Faster => "faster",
+            Action::Slower => "slower",
+            Action::StepBack => "step_back",
+            Action::Reverse => "reverse",
+            Action::JumpToStart => "jump_to_start",
+            Action::JumpToEnd => "jump_to_end",
+            Action::PanUp => "pan_up",
+            Action::PanDown => "pan_down",
+            Action::PanLeft => "pan_left",
+            Action::PanRight => "pan_right",
+            Action::ZoomIn => "zoom_in",
+            Action::ZoomOut => "zoom_out",
+            Action::ResetView => "reset_view",
+            Action::FitView => "fit_view",
+            Action::DeleteSelection => "delete_selection",
+            Action::Heavier => "heavier",
+            Action::Lighter => "lighter",
+            Action::KickUp => "kick_up",
+            Action::KickDown => "kick_down",
+            Action::KickLeft => "kick_left",
+            Action::KickRight => "kick_right",
+            Action::SaveGroup => "save_group",
+            Action::ClearSelection => "clear_selection",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+
+    /// Whether the keyboard's autorepeat triggers the action again while its key is held, so
+    /// holding the step keys scrubs through time. Everything else fires once per press.
+    pub fn repeats(self) -> bool {
+        matches!(self, Action::Step | Action::StepBack)
+    }
+}
+
+/// A key, optionally together with Shift.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binding {
+    pub key: KeyCode,
+    pub shift: bool,
+}
+
+impl Binding {
+    fn key(key: KeyCode) -> Self {
+        Self { key, shift: false }
+    }
+
+    fn shift(key: KeyCode) -> Self {
+        Self { key, shift: true }
+    }
+
+    /// Parses `Key` or `Shift+Key`, with key names as in `KEY_NAMES`.
+    fn parse(text: &str) -> Option<Self> {
+        let (shift, name) = match text.split_once('+') {
+            Some((modifier, name)) if modifier.trim().eq_ignore_ascii_case("shift") => {
+                (true, name.trim())
+            }
+            Some(_) => return None,
+            None => (false, text.trim()),
+        };
+        let key = KEY_NAMES
+            .iter()
+            .find(|(_, key_name)| key_name.eq_ignore_ascii_case(name))?
+            .0;
+        Some(Self { key, shift })
+    }
+
+    fn name(self) -> String {
+        let key = KEY_NAMES
+            .iter()
+            .find(|(key, _)| *key == self.key)
+            .map_or("?", |(_, name)| name);
+        if self.shift {
+            format!("Shift+{}", key)
+        } else {
+            key.to_string()
+        }
+    }
+}
+
+/// Keys that can be bound, by the name used in key binding files.
+const KEY_NAMES: &[(KeyCode, &str)] = &[
+    (KeyCode::A, "A"),
+    (KeyCode::B, "B"),
+    (KeyCode::C, "C"),
+    (KeyCode::D, "D"),
+    (KeyCode::E, "E"),
+    (KeyCode::F, "F"),
+    (KeyCode::G, "G"),
+    (KeyCode::H, "H"),
+    (KeyCode::I, "I"),
+    (KeyCode::J, "J"),
+    (KeyCode::K, "K"),
+    (KeyCode::L, "L"),
+    (KeyCode::M, "M"),
+    (KeyCode::N, "N"),
+    (KeyCode::O, "O"),
+    (KeyCode::P, "P"),
+    (KeyCode::Q, "Q"),
+    (KeyCode::R, "R"),
+    (KeyCode::S, "S"),
+    (KeyCode::T, "T"),
+    (KeyCode::U, "U"),
+    (KeyCode::V, "V"),
+    (KeyCode::W, "W"),
+    (KeyCode::X, "X"),
+    (KeyCode::Y, "Y"),
+    (KeyCode::Z, "Z"),
+    (KeyCode::Key0, "0"),
+    (KeyCode::Key1, "1"),
+    (KeyCode::Key2, "2"),
+    (KeyCode::Key3, "3"),
+    (KeyCode::Key4, "4"),
+    (KeyCode::Key5, "5"),
+    (KeyCode::Key6, "6"),
+    (KeyCode::Key7, "7"),
+    (KeyCode::Key8, "8"),
+    (KeyCode::Key9, "9"),
+    (KeyCode::F1, "F1"),
+    (KeyCode::F2, "F2"),
+    (KeyCode::F3, "F3"),
+    (KeyCode::F4, "F4"),
+    (KeyCode::F5, "F5"),
+    (KeyCode::F6, "F6"),
+    (KeyCode::F7, "F7"),
+    (KeyCode::F8, "F8"),
+    (KeyCode::F9, "F9"),
+    (KeyCode::F10, "F10"),
+    (KeyCode::F11, "F11"),
+    (KeyCode::F12, "F12"),
+    (KeyCode::Up, "Up"),
+    (KeyCode::Down, "Down"),
+    (KeyCode::Left, "Left"),
+    (KeyCode::Right, "Right"),
+    (KeyCode::Space, "Space"),
+    (KeyCode::Return, "Enter"),
+    (KeyCode::Tab, "Tab"),
+    (KeyCode::Escape, "Escape"),
+    (KeyCode::Back, "Backspace"),
+    (KeyCode::Delete, "Delete"),
+    (KeyCode::Insert, "Insert"),
+    (KeyCode::Home, "Home"),
+    (KeyCode::End, "End"),
+    (KeyCode::PageUp, "PageUp"),
+    (KeyCode::PageDown, "PageDown"),
+    (KeyCode::Period, "Period"),
+    (KeyCode::Comma, "Comma"),
+    (KeyCode::Equals, "Equals"),
+    (KeyCode::Minus, "Minus"),
+    (KeyCode::LBracket, "LBracket"),
+    (KeyCode::RBracket, "RBracket"),
+    (KeyCode::Semicolon, "Semicolon"),
+    (KeyCode::Apostrophe, "Apostrophe"),
+    (KeyCode::Slash, "Slash"),
+    (KeyCode::Backslash, "Backslash"),
+    (KeyCode::Grave, "Grave"),
+    (KeyCode::Numpad0, "Numpad0"),
+    (KeyCode::Numpad1, "Numpad1"),
+    (KeyCode::Numpad2, "Numpad2"),
+    (KeyCode::Numpad3, "Numpad3"),
+    (KeyCode::Numpad4, "Numpad4"),
+    (KeyCode::Numpad5, "Numpad5"),
+    (KeyCode::Numpad6, "Numpad6"),
+    (KeyCode::Numpad7, "Numpad7"),
+    (KeyCode::Numpad8, "Numpad8"),
+    (KeyCode::Numpad9, "Numpad9"),
+    (KeyCode::NumpadAdd, "NumpadAdd"),
+    (KeyCode::NumpadSubtract, "NumpadSubtract"),
+];
+
+/// Which keys trigger which actions.
+pub struct KeyBindings {
+    bindings: Vec<(Binding, Action)>,
+}
+
+impl KeyBindings {
+    pub fn defaults() -> Self {
+        let bindings = vec![
+            (Binding::key(KeyCode::R), Action::Record),
+            (Binding::key(KeyCode::S), Action::SaveVideo),
+            (Binding::key(KeyCode::E), Action::Export),
+            (Binding::key(KeyCode::Q), Action::DumpTree),
+            (Binding::key(KeyCode::T), Action::Trajectory),
+            (Binding::key(KeyCode::C), Action::ColorMode),
+            (Binding::key(KeyCode::V), Action::Colormap),
+            (Binding::key(KeyCode::L), Action::Legend),
+            (Binding::key(KeyCode::D), Action::DensityView),
+            (Binding::key(KeyCode::K), Action::Smoothing),
+            (Binding::key(KeyCode::J), Action::Trails),
+            (Binding::key(KeyCode::B), Action::Bloom),
+            (Binding::key(KeyCode::H), Action::Hud),
+            (Binding::key(KeyCode::G), Action::TreeBounds),
+            (Binding::key(KeyCode::M), Action::BoundsColoring),
+            (Binding::key(KeyCode::LBracket), Action::ShallowerTree),
+            (Binding::key(KeyCode::RBracket), Action::DeeperTree),
+            (Binding::key(KeyCode::F), Action::CameraLock),
+            (Binding::key(KeyCode::Space), Action::Pause),
+            (Binding::key(KeyCode::Period), Action::Step),
+            (Binding::key(KeyCode::N), Action::StepBatch),
+            (Binding::key(KeyCode::Equals), Action::Faster),
+            (Binding::key(KeyCode::Minus), Action::Slower),
+            (Binding::key(KeyCode::Comma), Action::StepBack),
+            (Binding::key(KeyCode::Back), Action::Reverse),
+            (Binding::key(KeyCode::Home), Action::JumpToStart),
+            (Binding::key(KeyCode::End), Action::JumpToEnd),
+            (Binding::key(KeyCode::Up), Action::PanUp),
+            (Binding::key(KeyCode::Down), Action::PanDown),
+            (Binding::key(KeyCode::Left), Action::PanLeft),
+            (Binding::key(KeyCode::Right), Action::PanRight),
+            (Binding::key(KeyCode::Z), Action::ZoomIn),
+            (Binding::key(KeyCode::X), Action::ZoomOut),
+            (Binding::key(KeyCode::O), Action::ResetView),
+            (Binding::key(KeyCode::A), Action::FitView),
+            (Binding::key(KeyCode::Delete), Action::DeleteSelection),
+            (Binding::key(KeyCode::PageUp), Action::Heavier),
+            (Binding::key(KeyCode::PageDown), Action::Lighter),
+            (Binding::shift(KeyCode::Up), Action::KickUp),
+            (Binding::shift(KeyCode::Down), Action::KickDown),
+            (Binding::shift(KeyCode::Left), Action::KickLeft),
+            (Binding::shift(KeyCode::Right), Action::KickRight),
+            (Binding::shift(KeyCode::E), Action::SaveGroup),
+            (Binding::key(KeyCode::Escape), Action::ClearSelection),
+        ];
+        Self { bindings }
+    }
+
+    /// The defaults with the actions listed in a key binding file rebound.
+    ///
+    /// Each line of the file reads `<action> = <keys>`, with a comma separated list of keys
+    /// such as `Space`, `PageUp` or `Shift+E`, and an empty list unbinding the action. Lines
+    /// starting with `#` are comments. `--print-keys` prints the defaults in this format.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut bindings = Self::defaults();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
+            let (name, keys) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected <action> = <keys>: {}", line)))?;
+            let action = Action::from_name(name.trim())
+                .ok_or_else(|| invalid(format!("unknown action: {}", name.trim())))?;
+            bindings.bindings.retain(|&(_, bound)| bound != action);
+            for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                let binding =
+                    Binding::parse(key).ok_or_else(|| invalid(format!("unknown key: {}", key)))?;
+                // A key does one thing, the binding from the file wins.
+                bindings.bindings.retain(|&(bound, _)| bound != binding);
+                bindings.bindings.push((binding, action));
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// The action of a key press. Keys bound without Shift also trigger with Shift held,
+    /// unless Shift + key is bound to something else.
+    pub fn action(&self, key: KeyCode, shift: bool) -> Option<Action> {
+        let find = |shift: bool| {
+            self.bindings
+                .iter()
+                .find(|(binding, _)| binding.key == key && binding.shift == shift)
+                .map(|&(_, action)| action)
+        };
+        find(shift).or_else(|| if shift { find(false) } else { None })
+    }
+
+    /// Whether one of the keys of `action` is held down.
+    pub fn is_held(&self, action: Action, keysdown: &[KeyCode], shift: bool) -> bool {
+        keysdown
+            .iter()
+            .any(|&key| self.action(key, shift) == Some(action))
+    }
+
+    /// The bindings in the key binding file format.
+    pub fn to_config(&self) -> String {
+        let mut config = String::from("# <action> = <keys>\n");
+        for action in Action::ALL {
+            let keys: Vec<String> = self
+                .bindings
+                .iter()
+                .filter(|&&(_, bound)| bound == action)
+                .map(|(binding, _)| binding.name())
+                .collect();
+            config.push_str(&format!("{} = {}\n", action.name(), keys.join(", ")));
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gp_keys_{}_{}", std::process::id(), name))
+    }
+
+    fn load_str(name: &str, config: &str) -> io::Result<KeyBindings> {
+        let path = scratch_path(name);
+        fs::write(&path, config).unwrap();
+        let bindings = KeyBindings::load(&path);
+        fs::remove_file(&path).unwrap();
+        bindings
+    }
+
+    #[test]
+    fn empty_list_unbinds_the_action() {
+        let bindings = load_str("unbind", "# no pausing\npause =\n").unwrap();
+        assert_eq!(bindings.action(KeyCode::Space, false), None);
+        assert!(bindings.to_config().contains("\npause = \n"));
+        assert_eq!(bindings.action(KeyCode::H, false), Some(Action::Hud));
+    }
+
+    #[test]
+    fn shift_bindings_are_parsed() {
+        let bindings = load_str("shift", "save_group = shift + S, Shift+F5\n").unwrap();
+        assert_eq!(bindings.action(KeyCode::S, true), Some(Action::SaveGroup));
+        assert_eq!(bindings.action(KeyCode::F5, true), Some(Action::SaveGroup));
+        // Without Shift the keys keep their own actions.
+        assert_eq!(bindings.action(KeyCode::S, false), Some(Action::SaveVideo));
+        assert_eq!(bindings.action(KeyCode::F5, false), None);
+        // The old Shift+E binding is gone, so Shift+E falls back to E.
+        assert_eq!(bindings.action(KeyCode::E, true), Some(Action::Export));
+    }
+
+    #[test]
+    fn other_modifiers_are_rejected() {
+        for key in ["Ctrl+E", "Alt+E", "Shift+Ctrl+E"] {
+            let err = load_str("modifier", &format!("export = {}\n", key))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("line 1"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn unknown_actions_and_keys_are_rejected() {
+        assert!(load_str("unknown_action", "explode = X\n").is_err());
+        assert!(load_str("unknown_key", "hud = Hyper\n").is_err());
+        assert!(load_str("no_equals", "hud H\n").is_err());
+    }
+
+    #[test]
+    fn file_binding_takes_the_key_from_its_default_action() {
+        let bindings = load_str("override", "hud = R\n").unwrap();
+        assert_eq!(bindings.action(KeyCode::R, false), Some(Action::Hud));
+        assert_eq!(bindings.action(KeyCode::H, false), None);
+        assert!(bindings.to_config().contains("\nrecord = \n"));
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let defaults = KeyBindings::defaults();
+        let loaded = load_str("defaults", &defaults.to_config()).unwrap();
+        assert_eq!(loaded.to_config(), defaults.to_config());
+
+        let changed =
+            load_str("changed", "pause = P, Enter\nkick_up = Shift+W\nlegend =\n").unwrap();
+        let reloaded = load_str("reloaded", &changed.to_config()).unwrap();
+        assert_eq!(reloaded.to_config(), changed.to_config());
+    }
+
+    #[test]
+    fn every_action_has_a_unique_name() {
+        for action in Action::ALL {
+            assert_eq!(Action::from_name(action.name()), Some(action));
+        }
+    }
+}
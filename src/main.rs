@@ -1,29 +1,104 @@
 #![feature(portable_simd)]
 
+mod bloom;
+mod camera;
+mod cli;
+mod coloring;
 mod consts;
+mod density;
+mod export;
+mod galaxy;
+mod headless;
+mod hud;
+mod import;
+mod isotropic;
+mod kepler;
+mod keys;
 mod particle;
+mod picture;
 mod quadtree;
+mod raster;
 mod rectangle;
+mod simulation;
+mod spawn;
+mod trails;
+mod trajectory;
 mod utils;
 
-use consts::{HEIGHT, MAX_ZOOM, WIDTH, WORLD_HEIGHT, WORLD_WIDTH};
+use bloom::Bloom;
+use camera::{center_on, fit, zoom_at, CameraLock, HOME_ORIGIN};
+use cli::Args;
+use coloring::{draw_colorbar, Coloring};
+use consts::{HEIGHT, MAX_ZOOM, WIDTH};
+use density::{DensityMap, RenderMode};
+use export::{create_export_dir, export_snapshot, export_tree, save_group, ExportFormat};
+use galaxy::{create_disk_galaxy, create_galaxy_collision, DiskGalaxy, GalaxyCollision};
 use ggez::event::{self, EventHandler};
 use ggez::graphics::{self, Color};
-use ggez::input::keyboard::{KeyCode, KeyInput};
+use ggez::input::keyboard::{KeyCode, KeyInput, KeyMods};
+use ggez::input::mouse::MouseButton;
 use ggez::GameError;
 use ggez::{conf, Context, ContextBuilder, GameResult};
+use hud::{draw_inspector, Hud};
+use isotropic::create_plummer;
+use kepler::create_planetary_system;
+use keys::{Action, KeyBindings};
 use nalgebra::Vector2;
-use particle::ParticleSystem;
-use quadtree::QuadTree;
+use particle::{ParticleBatch, ParticleSystem};
+use picture::create_from_image;
+use quadtree::{BoundsColoring, TreeView};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rectangle::Rectangle;
-use std::sync::{Arc, Mutex};
-use std::{env, fs};
+use simulation::{Simulation, TimeControl};
+use spawn::{SpawnKind, Spawner};
+use std::path::PathBuf;
+use std::process;
+use trails::Trails;
+use trajectory::{new_trajectory_path, Replay, Trajectory, TrajectoryWriter};
 use utils::{
-    clean_cache_images, convert_to_video, create_galaxy, create_quadtree, create_square,
-    move_on_mouse, rename_images, save_screen, screen_to_world_coords, spawn_circle, zoom_world,
+    clean_cache_images, convert_to_video, create_galaxy, create_perturbed_lattice,
+    create_results_dir, create_uniform_disk, load_initial_conditions, move_on_mouse, rename_images,
+    save_screen, screen_to_world_coords, zoom_world, PerturbedLattice,
 };
 
+/// Steps taken at once with `N`.
+const STEP_BATCH: u64 = 10;
+
+/// How far from a particle in screen pixels a right click still picks it.
+const PICK_RADIUS: f32 = 20.0;
+
+/// Right drags shorter than this in screen pixels count as a click.
+const BOX_SELECT_MIN: f32 = 4.0;
+
+/// Velocity added to the selected group per Shift + arrow press.
+const KICK: f32 = 0.05;
+
+/// Factor the selected group's mass is multiplied or divided by with Page Up / Page Down.
+const MASS_FACTOR: f32 = 2.0;
+
+/// Keyboard panning speed in screen pixels per second.
+const PAN_SPEED: f32 = 800.0;
+
+/// Factor the zoom changes by per second while a zoom key is held.
+const ZOOM_RATE: f32 = 2.0;
+
 fn main() {
+    let args = Args::parse();
+    create_results_dir();
+    println!("Seed {}", args.seed);
+
+    if args.headless {
+        let origin = HOME_ORIGIN;
+        headless::run(
+            &args,
+            create_scene(&args, &origin, MAX_ZOOM),
+            origin,
+            MAX_ZOOM,
+        );
+        return;
+    }
+
     let window_setup = conf::WindowSetup::default().title("Gravity Particles");
     let window_mode = conf::WindowMode::default()
         .dimensions(WIDTH, HEIGHT)
@@ -39,135 +114,671 @@ fn main() {
         Ok(_) => println!("Created initial cache folder"),
         Err(creating_error) => eprintln!("Error creating folder: {:?}", creating_error),
     }
-    let directory_name = "results";
-    let current_dir = env::current_dir().expect("Failed to get current directory");
-    let new_directory_path = current_dir.join(directory_name);
-    match fs::metadata(&new_directory_path) {
-        Ok(_) => println!("Results folder already exists"),
-        Err(_) => match fs::create_dir(&new_directory_path) {
-            Ok(_) => {
-                println!("Created initial results folder");
-            }
-            Err(e) => {
-                eprintln!("Error creating directory: {}", e);
-            }
-        },
-    }
 
-    let my_game = MyGame::new(&mut ctx);
+    let my_game = MyGame::new(&mut ctx, &args);
 
     event::run(ctx, event_loop, my_game);
 }
 
+fn create_scene(args: &Args, origin: &Vector2<f32>, zoom: f32) -> ParticleSystem {
+    let mut particles = ParticleSystem::with_capacity(4000);
+
+    if let Some(path) = &args.init {
+        load_initial_conditions(&mut particles, path);
+        particles.sort_by_mass();
+        return particles;
+    }
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let screen_center =
+        screen_to_world_coords(Vector2::new(WIDTH / 2.0, HEIGHT / 2.0), origin, zoom);
+    if let Some(path) = &args.image {
+        create_from_image(
+            &mut particles,
+            &mut rng,
+            path,
+            screen_center,
+            300.0,
+            40.0,
+            8000,
+        )
+        .unwrap_or_else(|e| panic!("Failed to load image {}: {}", path.display(), e));
+        particles.sort_by_mass();
+        return particles;
+    }
+
+    match args.scene.as_str() {
+        "galaxy" => create_galaxy(
+            &mut particles,
+            &mut rng,
+            screen_center,
+            Vector2::new(0.01, 0.01),
+            100.0,
+            10.0,
+            0.01,
+            4000,
+        ),
+        "plummer" => create_plummer(
+            &mut particles,
+            &mut rng,
+            screen_center,
+            Vector2::new(0.0, 0.0),
+            20.0,
+            40.0,
+            4000,
+        ),
+        "disk" => create_disk_galaxy(
+            &mut particles,
+            &mut rng,
+            &DiskGalaxy {
+                center: screen_center,
+                ..DiskGalaxy::default()
+            },
+        ),
+        "collision" => create_galaxy_collision(
+            &mut particles,
+            &mut rng,
+            &GalaxyCollision {
+                center: screen_center,
+                ..GalaxyCollision::default()
+            },
+        ),
+        "planets" => create_planetary_system(&mut particles, screen_center, Vector2::new(0.0, 0.0)),
+        "collapse" => create_uniform_disk(
+            &mut particles,
+            &mut rng,
+            screen_center,
+            Vector2::new(0.0, 0.0),
+            150.0,
+            40.0,
+            4000,
+        ),
+        "lattice" => create_perturbed_lattice(
+            &mut particles,
+            &mut rng,
+            &PerturbedLattice {
+                center: screen_center,
+                spectral_index: args.spectral_index,
+                ..PerturbedLattice::default()
+            },
+        ),
+        scene => panic!("Unknown scene: {}", scene),
+    }
+
+    //    let o2 = Vector2::new(-200.0, -200.0);
+    //    create_galaxy(
+    //        &mut particles,
+    //        &mut rng,
+    //        screen_to_world_coords(Vector2::new(WIDTH / 2.0, HEIGHT / 2.0), &o2, zoom),
+    //        Vector2::new(-0.1, -0.1),
+    //        50.0,
+    //        10.0,
+    //        0.001,
+    //        500,
+    //    );
+
+    //    create_square(
+    //        &mut particles,
+    //        &mut rng,
+    //        Vector2::new(100.0, 100.0),
+    //        200.0,
+    //        0.2,
+    //        Vector2::new(0.0, 0.0),
+    //        0.1,
+    //        100,
+    //    );
+
+    // Sort particles by mass
+    particles.sort_by_mass();
+    particles
+}
+
 struct MyGame {
     screen: graphics::ScreenImage,
-    qt: Arc<Mutex<QuadTree>>,
-    particles: ParticleSystem,
-    force_idxs: Vec<usize>,
+    sim: Simulation,
+    seed: u64,
+    export_formats: Vec<ExportFormat>,
+    export_dir: Option<PathBuf>,
+    trajectory: Option<TrajectoryWriter>,
+    replay: Option<Replay>,
+    keys: KeyBindings,
     keysdown: Vec<KeyCode>,
     origin: Vector2<f32>,
     zoom: f32,
     frame_count: u32,
     recording: bool,
-    max_vel_avg: f32,
-    min_vel_avg: f32,
-    vel_amount: u32,
-    // new fields for optimization of drawing/title and velocity sampling
-    last_fps: u32,
-    last_recording: bool,
-    vel_sample_counter: u32,
-    sample_interval: u32,
-    cached_max_vel: f32,
-    cached_min_vel: f32,
+    coloring: Coloring,
+    particle_batch: ParticleBatch,
+    render_mode: RenderMode,
+    density_map: DensityMap,
+    trails: Trails,
+    bloom: Bloom,
+    hud: Hud,
+    tree_view: TreeView,
+    time_control: TimeControl,
+    spawner: Spawner,
+    camera_lock: CameraLock,
+    /// Particle picked with the right mouse button.
+    selected: Option<usize>,
+    /// Screen position where the current right drag started.
+    box_start: Option<Vector2<f32>>,
+    /// Particles selected by dragging a box with the right mouse button.
+    group: Vec<usize>,
+    selection_batch: ParticleBatch,
+    // new fields for optimization of drawing/title
+    last_title: String,
 }
 
 impl MyGame {
-    pub fn new(ctx: &mut Context) -> MyGame {
-        let origin = Vector2::new(-100.0, -100.0);
+    pub fn new(ctx: &mut Context, args: &Args) -> MyGame {
+        let origin = HOME_ORIGIN;
         let zoom = MAX_ZOOM;
         let screen =
             graphics::ScreenImage::new(ctx, graphics::ImageFormat::Rgba8UnormSrgb, 1., 1., 1);
-        let qt = Arc::new(Mutex::new(QuadTree::new(Rectangle::new(
-            Vector2::new(0.0, 0.0),
-            WORLD_WIDTH,
-            WORLD_HEIGHT,
-        ))));
-
-        let mut particles = ParticleSystem::with_capacity(4000);
-
-        create_galaxy(
-            &mut particles,
-            screen_to_world_coords(Vector2::new(WIDTH / 2.0, HEIGHT / 2.0), &origin, zoom),
-            Vector2::new(0.01, 0.01),
-            100.0,
-            10.0,
-            0.01,
-            4000,
-        );
-
-        let o2 = Vector2::new(-200.0, -200.0);
-//        create_galaxy(
-//            &mut particles,
-//            screen_to_world_coords(Vector2::new(WIDTH / 2.0, HEIGHT / 2.0), &o2, zoom),
-//            Vector2::new(-0.1, -0.1),
-//            50.0,
-//            10.0,
-//            0.001,
-//            500,
-//        );
-
-//        create_square(
-//            &mut particles,
-//            Vector2::new(100.0, 100.0),
-//            200.0,
-//            0.2,
-//            Vector2::new(0.0, 0.0),
-//            0.1,
-//            100,
-//        );
-
-        // Sort particles by mass
-        particles.sort_by_mass();
+        let replay = args.replay.as_ref().map(|path| {
+            let trajectory = Trajectory::open(path)
+                .unwrap_or_else(|e| panic!("Failed to open trajectory {}: {}", path.display(), e));
+            assert!(
+                !trajectory.is_empty(),
+                "Trajectory {} has no frames",
+                path.display()
+            );
+            println!(
+                "Replaying {} frames from {} (seed {})",
+                trajectory.len(),
+                path.display(),
+                trajectory.seed
+            );
+            Replay::new(trajectory)
+        });
+        let coloring = Coloring::new(args.initial_color_mode(), args.colormap);
+        let particles = match replay {
+            Some(_) => ParticleSystem::new(),
+            None => create_scene(args, &origin, zoom),
+        };
+        let keys = match &args.keys {
+            Some(path) => KeyBindings::load(path).unwrap_or_else(|e| {
+                eprintln!("Failed to load key bindings from {}: {}", path.display(), e);
+                process::exit(1);
+            }),
+            None => KeyBindings::defaults(),
+        };
+        let mut spawner = Spawner::new(args.seed);
+        if let Some(path) = &args.group {
+            let mut group = ParticleSystem::new();
+            load_initial_conditions(&mut group, path);
+            spawner.set_group(group);
+        }
 
         MyGame {
             screen,
-            qt,
-            particles,
-            force_idxs: Vec::with_capacity(4000),
+            sim: Simulation::new(particles),
+            // Snapshots taken during a replay belong to the recorded scene.
+            seed: replay
+                .as_ref()
+                .map_or(args.seed, |replay| replay.trajectory.seed),
+            export_formats: args.export_formats.clone(),
+            export_dir: None,
+            trajectory: None,
+            replay,
+            keys,
             keysdown: Vec::new(),
             origin,
             zoom,
             frame_count: 0,
             recording: false,
-            max_vel_avg: 0.0,
-            min_vel_avg: 0.0,
-            vel_amount: 0,
-            last_fps: 0,
-            last_recording: false,
-            vel_sample_counter: 0,
-            sample_interval: 1, // sample velocities every 5 frames
-            cached_max_vel: 0.0,
-            cached_min_vel: 0.0,
+            coloring,
+            particle_batch: ParticleBatch::new(ctx),
+            render_mode: args.render_mode,
+            density_map: DensityMap::new(WIDTH as u32, HEIGHT as u32, args.smoothing),
+            trails: Trails::new(args.trails, args.trail_length, args.trail_opacity),
+            bloom: Bloom::new(args.bloom, args.bloom_intensity, args.bloom_radius),
+            hud: Hud::new(args.hud),
+            tree_view: TreeView::new(),
+            time_control: TimeControl::new(1.0),
+            spawner,
+            camera_lock: CameraLock::Free,
+            selected: None,
+            box_start: None,
+            group: Vec::new(),
+            selection_batch: ParticleBatch::new(ctx),
+            last_title: String::new(),
+        }
+    }
+
+    fn ensure_export_dir(&mut self) -> Option<PathBuf> {
+        if self.export_dir.is_none() {
+            match create_export_dir(self.seed) {
+                Ok(dir) => self.export_dir = Some(dir),
+                Err(e) => eprintln!("Error creating export folder: {}", e),
+            }
+        }
+        self.export_dir.clone()
+    }
+
+    fn export_snapshot(&mut self) {
+        let Some(dir) = self.ensure_export_dir() else {
+            return;
+        };
+        for &format in &self.export_formats {
+            match export_snapshot(
+                &self.sim.particles,
+                format,
+                self.sim.clock(),
+                self.seed,
+                &dir,
+            ) {
+                Ok(path) => println!("Exported snapshot to {}", path.display()),
+                Err(e) => eprintln!("Error exporting snapshot: {}", e),
+            }
+        }
+    }
+
+    fn export_tree(&mut self) {
+        let qt = &self.sim.qt;
+        println!("Quadtree at step {}: {}", self.sim.steps, qt.stats());
+        let Some(dir) = self.ensure_export_dir() else {
+            return;
+        };
+        match export_tree(&self.sim.qt, self.sim.steps, &dir) {
+            Ok(path) => println!("Exported quadtree to {}.{{json,dot}}", path.display()),
+            Err(e) => eprintln!("Error exporting quadtree: {}", e),
+        }
+    }
+
+    fn toggle_trajectory_recording(&mut self) {
+        if let Some(writer) = self.trajectory.take() {
+            let frames = writer.frames;
+            match writer.finish() {
+                Ok(_) => println!("Saved trajectory with {} frames", frames),
+                Err(e) => eprintln!("Error saving trajectory: {}", e),
+            }
+            return;
+        }
+        let path = new_trajectory_path(self.seed);
+        match TrajectoryWriter::create(&path, self.seed) {
+            Ok(mut writer) => {
+                println!("Recording trajectory to {}", path.display());
+                // Start with the current state, like headless recordings do.
+                if let Err(e) = writer.write_frame(&self.sim.particles, self.sim.clock()) {
+                    eprintln!("Error writing trajectory frame: {}", e);
+                }
+                self.trajectory = Some(writer);
+            }
+            Err(e) => eprintln!("Error creating trajectory file: {}", e),
+        }
+    }
+
+    /// HUD lines about the window: zoom, frame rate and what is being recorded or replayed.
+    fn hud_view_lines(&self, ctx: &Context) -> Vec<String> {
+        let mut lines = vec![
+            format!("Zoom: {:.2}x", self.zoom),
+            format!("Camera: {}", self.camera_lock.name()),
+            format!("FPS: {:.0}", ctx.time.fps()),
+        ];
+        if !self.group.is_empty() {
+            lines.push(format!("Selected: {} particles", self.group.len()));
+        }
+        if self.replay.is_none() {
+            lines.push(format!(
+                "Speed: {}x{}",
+                self.time_control.speed,
+                if self.time_control.paused {
+                    " paused"
+                } else {
+                    ""
+                }
+            ));
+        }
+        if self.tree_view.enabled {
+            lines.push(format!(
+                "Tree view: {} levels, by {}",
+                self.tree_view
+                    .max_depth
+                    .map_or("all".to_string(), |depth| format!("0-{}", depth)),
+                match self.tree_view.coloring {
+                    BoundsColoring::Depth => "depth",
+                    BoundsColoring::Mass => "mass",
+                }
+            ));
+        }
+        if self.recording {
+            lines.push(format!("Recording video: {} frames", self.frame_count));
+        }
+        if let Some(writer) = &self.trajectory {
+            lines.push(format!("Recording trajectory: {} frames", writer.frames));
+        }
+        if !self.recording && self.trajectory.is_none() {
+            lines.push("Recording: off".to_string());
+        }
+        if let Some(replay) = &self.replay {
+            lines.push(format!(
+                "Replay: frame {}/{}, {}x{}",
+                replay.current_frame() + 1,
+                replay.trajectory.len(),
+                replay.speed,
+                if replay.paused { " paused" } else { "" }
+            ));
+            lines.push(format!("Replay seed: {}", replay.trajectory.seed));
+        }
+        lines
+    }
+
+    /// Draws the density map of the whole window as one image.
+    fn draw_density(&mut self, canvas: &mut graphics::Canvas, ctx: &mut Context) {
+        let frame = self.density_map.render(
+            &self.sim.particles,
+            self.coloring.colormap,
+            self.origin,
+            self.zoom,
+        );
+        let image = graphics::Image::from_pixels(
+            ctx,
+            frame.as_raw(),
+            graphics::ImageFormat::Rgba8UnormSrgb,
+            frame.width(),
+            frame.height(),
+        );
+        canvas.draw(&image, graphics::DrawParam::default());
+    }
+
+    /// Selects the particle closest to `screen_pos`, or clears the selection if none is near.
+    fn pick(&mut self, screen_pos: Vector2<f32>) {
+        let world_pos = screen_to_world_coords(screen_pos, &self.origin, self.zoom);
+        // Clicking next to a particle is enough, clicking empty space clears the selection.
+        let reach = PICK_RADIUS / self.zoom;
+        let area = Rectangle::new(
+            world_pos - Vector2::new(reach, reach),
+            2.0 * reach,
+            2.0 * reach,
+        );
+        let candidates = self.sim.qt.query(&area, &self.sim.particles);
+        self.selected = self.sim.particles.nearest(&candidates, world_pos, reach);
+    }
+
+    /// Selects all particles inside the box spanned by two screen corners.
+    fn select_box(&mut self, from: Vector2<f32>, to: Vector2<f32>) {
+        let a = screen_to_world_coords(from, &self.origin, self.zoom);
+        let b = screen_to_world_coords(to, &self.origin, self.zoom);
+        let area = Rectangle::new(a.inf(&b), (a.x - b.x).abs(), (a.y - b.y).abs());
+        self.group = self.sim.qt.query(&area, &self.sim.particles);
+        self.group.sort_unstable();
+        println!("Selected {} particles", self.group.len());
+    }
+
+    /// Removes the selected group from the simulation.
+    fn delete_group(&mut self) {
+        if self.group.is_empty() {
+            return;
+        }
+        self.sim.particles.remove(&self.group);
+        // Indices after the removed particles shift down.
+        self.selected = self
+            .selected
+            .and_then(|idx| match self.group.binary_search(&idx) {
+                Ok(_) => None,
+                Err(removed_before) => Some(idx - removed_before),
+            });
+        println!("Deleted {} particles", self.group.len());
+        self.group.clear();
+        self.sim.rebuild_tree();
+        self.trails.clear();
+        self.hud.reset_energy();
+    }
+
+    fn scale_group_mass(&mut self, factor: f32) {
+        for &i in &self.group {
+            self.sim.particles.mass[i] *= factor;
+        }
+        self.sim.rebuild_tree();
+        self.hud.reset_energy();
+    }
+
+    fn kick_group(&mut self, kick: Vector2<f32>) {
+        for &i in &self.group {
+            self.sim.particles.vel_x[i] += kick.x;
+            self.sim.particles.vel_y[i] += kick.y;
+        }
+        self.hud.reset_energy();
+    }
+
+    /// Saves the selected group to a file and keeps it for dropping with Alt + middle click.
+    fn save_group(&mut self) {
+        if self.group.is_empty() {
+            return;
+        }
+        let group = self.sim.particles.subset(&self.group);
+        match save_group(&group, self.seed, self.sim.clock()) {
+            Ok(path) => println!("Saved {} particles to {}", group.count, path.display()),
+            Err(e) => eprintln!("Error saving particle group: {}", e),
+        }
+        self.spawner.set_group(group);
+    }
+
+    /// Edits of the box selection, only while the simulation is live.
+    fn group_action(&mut self, action: Action) {
+        if self.replay.is_some() || self.group.is_empty() {
+            return;
+        }
+        match action {
+            Action::DeleteSelection => self.delete_group(),
+            Action::Heavier => self.scale_group_mass(MASS_FACTOR),
+            Action::Lighter => self.scale_group_mass(1.0 / MASS_FACTOR),
+            Action::KickUp => self.kick_group(Vector2::new(0.0, -KICK)),
+            Action::KickDown => self.kick_group(Vector2::new(0.0, KICK)),
+            Action::KickLeft => self.kick_group(Vector2::new(-KICK, 0.0)),
+            Action::KickRight => self.kick_group(Vector2::new(KICK, 0.0)),
+            Action::SaveGroup => self.save_group(),
+            Action::ClearSelection => self.group.clear(),
+            _ => {}
+        }
+    }
+
+    /// Highlights the box selection and outlines the box being dragged.
+    fn draw_selection(&mut self, canvas: &mut graphics::Canvas, ctx: &mut Context) {
+        if !self.group.is_empty() {
+            let colors = vec![Color::WHITE; self.sim.particles.count];
+            self.selection_batch.draw(
+                canvas,
+                &self.sim.particles,
+                &self.group,
+                &colors,
+                self.origin,
+                self.zoom,
+            );
+        }
+        if let Some(start) = self.box_start {
+            let mouse = Vector2::new(ctx.mouse.position().x, ctx.mouse.position().y);
+            let corner = start.inf(&mouse);
+            let size = (start - mouse).abs();
+            if let Ok(mesh) = graphics::Mesh::new_rectangle(
+                ctx,
+                graphics::DrawMode::stroke(2.0),
+                graphics::Rect::new(corner.x, corner.y, size.x, size.y),
+                Color::WHITE,
+            ) {
+                canvas.draw(&mesh, graphics::DrawParam::default());
+            }
+        }
+    }
+
+    fn time_action(&mut self, action: Action) {
+        if self.replay.is_some() {
+            return;
+        }
+        let time = &mut self.time_control;
+        match action {
+            Action::Pause => time.paused = !time.paused,
+            Action::Step => time.step_by(1),
+            Action::StepBatch => time.step_by(STEP_BATCH),
+            Action::Faster => time.faster(),
+            Action::Slower => time.slower(),
+            _ => {}
+        }
+    }
+
+    fn replay_action(&mut self, action: Action) {
+        let Some(replay) = &mut self.replay else {
+            return;
+        };
+        match action {
+            Action::Pause => replay.paused = !replay.paused,
+            Action::Step => replay.step_by(1),
+            Action::StepBack => replay.step_by(-1),
+            Action::Faster => replay.faster(),
+            Action::Slower => replay.slower(),
+            Action::Reverse => replay.reverse(),
+            Action::JumpToStart => replay.jump_to_start(),
+            Action::JumpToEnd => replay.jump_to_end(),
+            _ => {}
+        }
+    }
+
+    fn camera_action(&mut self, action: Action) {
+        match action {
+            Action::CameraLock => {
+                self.camera_lock = self.camera_lock.next(self.selected.is_some());
+            }
+            Action::ResetView => {
+                self.origin = HOME_ORIGIN;
+                self.zoom = MAX_ZOOM;
+                self.camera_lock = CameraLock::Free;
+            }
+            Action::FitView => {
+                if let Some((origin, zoom)) = fit(&self.sim.particles) {
+                    self.origin = origin;
+                    self.zoom = zoom;
+                    self.camera_lock = CameraLock::Free;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Pans and zooms for as long as the keys are held, at a speed independent of the frame
+    /// rate. Zooming keeps the middle of the window in place.
+    fn move_on_keys(&mut self, ctx: &Context) {
+        let shift = ctx.keyboard.is_mod_active(KeyMods::SHIFT);
+        let held = |action| self.keys.is_held(action, &self.keysdown, shift);
+        let mut pan = Vector2::zeros();
+        if held(Action::PanUp) {
+            pan.y += 1.0;
+        }
+        if held(Action::PanDown) {
+            pan.y -= 1.0;
+        }
+        if held(Action::PanLeft) {
+            pan.x += 1.0;
+        }
+        if held(Action::PanRight) {
+            pan.x -= 1.0;
+        }
+        let mut zoom_direction = 0.0;
+        if held(Action::ZoomIn) {
+            zoom_direction += 1.0;
+        }
+        if held(Action::ZoomOut) {
+            zoom_direction -= 1.0;
+        }
+
+        let elapsed = ctx.time.delta().as_secs_f32();
+        if pan != Vector2::zeros() {
+            self.origin += pan * PAN_SPEED * elapsed / self.zoom;
+            self.camera_lock = CameraLock::Free;
+        }
+        if zoom_direction != 0.0 {
+            let center = Vector2::new(WIDTH / 2.0, HEIGHT / 2.0);
+            let factor = ZOOM_RATE.powf(zoom_direction * elapsed);
+            zoom_at(&mut self.origin, &mut self.zoom, center, factor);
+        }
+    }
+
+    fn perform(&mut self, ctx: &mut Context, action: Action) {
+        match action {
+            Action::Record => {
+                self.recording = true;
+                println!("Recording!");
+            }
+            Action::SaveVideo => {
+                self.recording = false;
+                println!("Saving video to project folder (results)...");
+                rename_images(ctx);
+                convert_to_video(ctx, self.seed);
+                clean_cache_images(ctx);
+                println!("Saved!");
+            }
+            Action::Export => self.export_snapshot(),
+            Action::DumpTree => self.export_tree(),
+            Action::Trajectory if self.replay.is_none() => self.toggle_trajectory_recording(),
+            Action::ColorMode => self.coloring.next_mode(),
+            Action::Colormap => self.coloring.next_colormap(),
+            Action::Legend => self.coloring.show_legend = !self.coloring.show_legend,
+            Action::DensityView => self.render_mode = self.render_mode.toggle(),
+            Action::Smoothing => self.density_map.smooth = !self.density_map.smooth,
+            Action::Trails => {
+                self.trails.enabled = !self.trails.enabled;
+                self.trails.clear();
+            }
+            Action::Bloom => self.bloom.enabled = !self.bloom.enabled,
+            Action::Hud => self.hud.visible = !self.hud.visible,
+            Action::TreeBounds => self.tree_view.enabled = !self.tree_view.enabled,
+            Action::BoundsColoring => self.tree_view.next_coloring(),
+            Action::ShallowerTree => self.tree_view.shallower(self.sim.qt.stats().max_depth),
+            Action::DeeperTree => self.tree_view.deeper(self.sim.qt.stats().max_depth),
+            _ => {
+                self.time_action(action);
+                self.replay_action(action);
+                self.camera_action(action);
+                self.group_action(action);
+            }
         }
     }
 }
 
 impl EventHandler for MyGame {
     fn update(&mut self, ctx: &mut Context) -> GameResult {
+        if self
+            .selected
+            .is_some_and(|idx| idx >= self.sim.particles.count)
         {
-            let mut qt_lock = self.qt.lock().unwrap();
-            // Rebuild the quadtree in-place instead of replacing the Arc/Mutex each frame.
-            *qt_lock = create_quadtree(&self.particles);
-            self.particles.reset_all_net_force();
-            // Reuse the same index buffer to avoid allocating every frame.
-            self.force_idxs.resize(self.particles.count, 0);
-            for (i, slot) in self.force_idxs.iter_mut().enumerate() {
-                *slot = i;
+            self.selected = None;
+        }
+        let count = self.sim.particles.count;
+        self.group.retain(|&idx| idx < count);
+        if let Some(replay) = &mut self.replay {
+            // Replays only swap in recorded states, the physics is never stepped.
+            replay.advance();
+            if let Some(frame) = replay.take_changed_frame() {
+                match replay.trajectory.frame(frame) {
+                    Ok((clock, particles)) => {
+                        self.sim.load_state(particles, clock);
+                        self.trails.record(&self.sim.particles, self.sim.steps);
+                    }
+                    Err(e) => eprintln!("Error reading trajectory frame: {}", e),
+                }
+            }
+        } else {
+            let (steps, dt) = self.time_control.frame_steps();
+            self.sim.dt = dt;
+            for _ in 0..steps {
+                self.sim.step();
+                if let Some(writer) = &mut self.trajectory {
+                    if let Err(e) = writer.write_frame(&self.sim.particles, self.sim.clock()) {
+                        eprintln!("Error writing trajectory frame: {}", e);
+                    }
+                }
+            }
+            if steps > 0 {
+                self.trails.record(&self.sim.particles, self.sim.steps);
             }
-            qt_lock.calculate_force_simd(&mut self.particles, &self.force_idxs);
         }
-        self.particles.apply_forces_simd();
+        // The camera keeps working while paused.
         move_on_mouse(ctx, &mut self.origin, self.zoom);
+        self.move_on_keys(ctx);
+        match self.camera_lock.target(&self.sim.particles, self.selected) {
+            Some(target) => center_on(&mut self.origin, self.zoom, target),
+            None => self.camera_lock = CameraLock::Free,
+        }
         Ok(())
     }
 
@@ -180,50 +791,140 @@ impl EventHandler for MyGame {
             HEIGHT / self.zoom,
         );
 
-        // Sample and update velocity averages only every `sample_interval` frames.
         let fps_u32 = ctx.time.fps() as u32;
-        if self.vel_sample_counter == 0 {
-            let max_vel = self.particles.find_max_velocity_norm();
-            let min_vel = self.particles.find_min_velocity_norm();
-            self.cached_max_vel = max_vel;
-            self.cached_min_vel = min_vel;
-
-            self.max_vel_avg = (self.max_vel_avg * self.vel_amount as f32 + max_vel)
-                / (self.vel_amount as f32 + 1.0);
-            self.min_vel_avg = (self.min_vel_avg * self.vel_amount as f32 + min_vel)
-                / (self.vel_amount as f32 + 1.0);
-            self.vel_amount += 1;
-        }
-        self.vel_sample_counter = (self.vel_sample_counter + 1) % self.sample_interval;
-
-        let locked_qt = self.qt.lock().unwrap();
-        let particles_to_draw = locked_qt.query(&draw_query_area, &self.particles);
-        locked_qt.show(
+        match self.render_mode {
+            RenderMode::Points => {
+                let colors = self.coloring.colors(&self.sim.particles, &self.sim.qt);
+                let particles_to_draw = self.sim.qt.query(&draw_query_area, &self.sim.particles);
+                self.trails.draw(
+                    &mut canvas,
+                    ctx,
+                    &self.sim.particles,
+                    &particles_to_draw,
+                    &colors,
+                    self.origin,
+                    self.zoom,
+                );
+                self.particle_batch.draw(
+                    &mut canvas,
+                    &self.sim.particles,
+                    &particles_to_draw,
+                    &colors,
+                    self.origin,
+                    self.zoom,
+                );
+            }
+            RenderMode::Density => self.draw_density(&mut canvas, ctx),
+        }
+        self.sim.qt.show(
             &mut canvas,
             ctx,
             self.origin,
             self.zoom,
-            &particles_to_draw,
-            &self.particles,
-            self.max_vel_avg,
-            self.min_vel_avg,
-            false,
+            &self.tree_view,
+            self.coloring.colormap,
         );
+        if let Some(idx) = self.selected {
+            self.sim.qt.show_force_walk(
+                &mut canvas,
+                ctx,
+                &self.sim.particles,
+                idx,
+                self.origin,
+                self.zoom,
+            );
+        }
+        self.draw_selection(&mut canvas, ctx);
+        match self.render_mode {
+            RenderMode::Points => self.coloring.draw_legend(&mut canvas, ctx),
+            RenderMode::Density if self.coloring.show_legend => {
+                let (min, max) = self.density_map.range();
+                let colormap = self.coloring.colormap;
+                draw_colorbar(
+                    &mut canvas,
+                    ctx,
+                    |t| colormap.sample(t),
+                    format!("density ({})", self.coloring.colormap.name()),
+                    format!("{:.3e}", min),
+                    format!("{:.3e}", max),
+                );
+            }
+            RenderMode::Density => {}
+        }
+        self.spawner.show_preview(
+            &mut canvas,
+            ctx,
+            Vector2::new(ctx.mouse.position().x, ctx.mouse.position().y),
+            self.origin,
+            self.zoom,
+        );
+        if let Some(idx) = self.selected {
+            draw_inspector(
+                &mut canvas,
+                ctx,
+                &self.sim.particles,
+                idx,
+                self.camera_lock == CameraLock::Particle,
+            );
+        }
+        if self.hud.visible {
+            let view_lines = self.hud_view_lines(ctx);
+            self.hud.draw(&mut canvas, ctx, &self.sim, &view_lines);
+        }
 
-        // Update title only when fps or recording state changes.
         if self.recording {
             self.frame_count += 1;
-            save_screen(ctx, &mut self.screen, self.frame_count);
+            save_screen(ctx, &mut self.screen, self.frame_count, &self.bloom);
         }
-        if fps_u32 != self.last_fps || self.recording != self.last_recording {
-            let title = if self.recording {
-                format!("FPS: {} Recording...", fps_u32)
-            } else {
-                format!("FPS: {}", fps_u32)
-            };
+        // Update title only when its contents change.
+        let mut title = match self.render_mode {
+            RenderMode::Points => format!(
+                "FPS: {} Color: {} ({})",
+                fps_u32,
+                self.coloring.mode.name(),
+                self.coloring.colormap.name()
+            ),
+            RenderMode::Density => format!(
+                "FPS: {} Density ({}{})",
+                fps_u32,
+                self.coloring.colormap.name(),
+                if self.density_map.smooth {
+                    ", smoothed"
+                } else {
+                    ""
+                }
+            ),
+        };
+        if self.recording {
+            title.push_str(" Recording...");
+        }
+        if self.bloom.enabled {
+            title.push_str(" Bloom");
+        }
+        if self.replay.is_none() {
+            if self.time_control.paused {
+                title.push_str(" Paused");
+            }
+            if self.time_control.speed != 1.0 {
+                title.push_str(&format!(" Speed {}x", self.time_control.speed));
+            }
+        }
+        if self.trajectory.is_some() {
+            title.push_str(" Recording trajectory...");
+        }
+        if let Some(replay) = &self.replay {
+            title.push_str(&format!(
+                " Replay frame {}/{} (step {}) {}x{}",
+                replay.current_frame() + 1,
+                replay.trajectory.len(),
+                self.sim.steps,
+                replay.speed,
+                if replay.paused { " paused" } else { "" }
+            ));
+        }
+        if title != self.last_title {
             ctx.gfx.set_window_title(title.as_str());
-            self.last_fps = fps_u32;
-            self.last_recording = self.recording;
+            self.last_title = title;
         }
         canvas.finish(ctx)?;
         ctx.gfx.present(&self.screen.image(ctx))?;
@@ -234,23 +935,17 @@ impl EventHandler for MyGame {
         &mut self,
         ctx: &mut Context,
         keyinput: KeyInput,
-        _repeat: bool,
+        repeat: bool,
     ) -> Result<(), GameError> {
         if let Some(keycode) = keyinput.keycode {
-            self.keysdown.push(keycode);
-            self.keysdown.dedup_by_key(|x| *x);
-
-            if keycode == KeyCode::R {
-                self.recording = true;
-                println!("Recording!");
+            if !self.keysdown.contains(&keycode) {
+                self.keysdown.push(keycode);
             }
-            if keycode == KeyCode::S {
-                self.recording = false;
-                println!("Saving video to project folder (results)...");
-                rename_images(ctx);
-                convert_to_video(ctx);
-                clean_cache_images(ctx);
-                println!("Saved!");
+            let shift = ctx.keyboard.is_mod_active(KeyMods::SHIFT);
+            if let Some(action) = self.keys.action(keycode, shift) {
+                if !repeat || action.repeats() {
+                    self.perform(ctx, action);
+                }
             }
         }
         Ok(())
@@ -263,6 +958,67 @@ impl EventHandler for MyGame {
         Ok(())
     }
 
+    fn mouse_button_down_event(
+        &mut self,
+        _ctx: &mut Context,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    ) -> Result<(), GameError> {
+        if button == MouseButton::Right {
+            self.box_start = Some(Vector2::new(x, y));
+        }
+        if button == MouseButton::Left {
+            // Dragging the view takes the camera back from a lock.
+            self.camera_lock = CameraLock::Free;
+        }
+        if button == MouseButton::Middle && self.replay.is_none() {
+            let world_pos = screen_to_world_coords(Vector2::new(x, y), &self.origin, self.zoom);
+            self.spawner.begin(world_pos);
+        }
+        Ok(())
+    }
+
+    fn mouse_button_up_event(
+        &mut self,
+        ctx: &mut Context,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    ) -> Result<(), GameError> {
+        if button == MouseButton::Right {
+            if let Some(start) = self.box_start.take() {
+                let end = Vector2::new(x, y);
+                if (end - start).norm() < BOX_SELECT_MIN {
+                    self.pick(end);
+                } else {
+                    self.select_box(start, end);
+                }
+            }
+        }
+        if button == MouseButton::Middle {
+            let kind = if ctx.keyboard.is_mod_active(KeyMods::ALT) {
+                SpawnKind::Group
+            } else if ctx.keyboard.is_mod_active(KeyMods::SHIFT) {
+                SpawnKind::Galaxy
+            } else if ctx.keyboard.is_mod_active(KeyMods::CTRL) {
+                SpawnKind::Cluster
+            } else {
+                SpawnKind::Particle
+            };
+            let world_pos = screen_to_world_coords(Vector2::new(x, y), &self.origin, self.zoom);
+            if self
+                .spawner
+                .finish(&mut self.sim.particles, world_pos, kind)
+            {
+                self.sim.rebuild_tree();
+                self.trails.clear();
+                self.hud.reset_energy();
+            }
+        }
+        Ok(())
+    }
+
     fn mouse_wheel_event(&mut self, ctx: &mut Context, _x: f32, y: f32) -> Result<(), GameError> {
         zoom_world(ctx, &mut self.origin, &mut self.zoom, y);
 
@@ -2,13 +2,13 @@ use std::simd::*;
 
 use crate::utils::world_to_screen_coords;
 use ggez::{
-    graphics::{self, Canvas, Color},
-    mint::Point2,
+    graphics::{self, Canvas, Color, DrawParam, InstanceArray, Mesh},
     Context,
 };
 use nalgebra::Vector2;
 
-use crate::consts::{G, LANES, SOFTENING};
+use crate::consts::{G, SOFTENING};
+use crate::galaxy::StaticHalo;
 
 #[derive(Clone, Debug)]
 pub struct ParticleSystem {
@@ -28,6 +28,14 @@ pub struct ParticleSystem {
     pub mass: Vec<f32>,
     pub radius: Vec<f32>,
     pub indices: Vec<usize>,
+    // Fixed display color, e.g. the pixel a particle was sampled from
+    pub color: Vec<Option<Color>>,
+    // Which generator (or loaded file) each particle came from, see `start_species`
+    pub species: Vec<u32>,
+    current_species: u32,
+
+    // Fixed external potentials acting on every particle
+    pub static_halos: Vec<StaticHalo>,
 
     // Number of particles
     pub count: usize,
@@ -46,6 +54,10 @@ impl ParticleSystem {
             mass: Vec::new(),
             radius: Vec::new(),
             indices: Vec::new(),
+            color: Vec::new(),
+            species: Vec::new(),
+            current_species: 0,
+            static_halos: Vec::new(),
             count: 0,
         }
     }
@@ -61,6 +73,10 @@ impl ParticleSystem {
             mass: Vec::with_capacity(capacity),
             radius: Vec::with_capacity(capacity),
             indices: Vec::with_capacity(capacity),
+            color: Vec::with_capacity(capacity),
+            species: Vec::with_capacity(capacity),
+            current_species: 0,
+            static_halos: Vec::new(),
             count: 0,
         }
     }
@@ -82,13 +98,34 @@ impl ParticleSystem {
         self.mass.push(mass);
         self.radius.push(radius);
         self.indices.push(index);
+        self.color.push(None);
+        self.species.push(self.current_species);
         self.count += 1;
     }
 
+    /// Tags the particles added from now on as a new species, above every one in use.
+    pub fn start_species(&mut self) {
+        self.current_species = self.species.iter().max().map_or(0, |s| s + 1);
+    }
+
+    /// An id that no particle has yet, for adding particles to a running system.
+    pub fn next_id(&self) -> usize {
+        self.indices.iter().max().map_or(0, |&id| id + 1)
+    }
+
+    /// Adds `offset` to the ids of the particles from index `first` on, for generators that
+    /// number their particles from 0.
+    pub fn offset_ids(&mut self, first: usize, offset: usize) {
+        for id in &mut self.indices[first..] {
+            *id += offset;
+        }
+    }
+
     pub fn get_position(&self, idx: usize) -> Vector2<f32> {
         Vector2::new(self.pos_x[idx], self.pos_y[idx])
     }
 
+    #[allow(dead_code)]
     pub fn set_position(&mut self, idx: usize, pos: Vector2<f32>) {
         self.pos_x[idx] = pos.x;
         self.pos_y[idx] = pos.y;
@@ -98,6 +135,7 @@ impl ParticleSystem {
         Vector2::new(self.vel_x[idx], self.vel_y[idx])
     }
 
+    #[allow(dead_code)]
     pub fn set_velocity(&mut self, idx: usize, vel: Vector2<f32>) {
         self.vel_x[idx] = vel.x;
         self.vel_y[idx] = vel.y;
@@ -113,6 +151,7 @@ impl ParticleSystem {
         self.net_force_y[idx] = force.y;
     }
 
+    #[allow(dead_code)]
     pub fn reset_net_force(&mut self, idx: usize) {
         self.net_force_x[idx] = 0.0;
         self.net_force_y[idx] = 0.0;
@@ -132,8 +171,21 @@ impl ParticleSystem {
         self.net_force_y[idx] += force.y;
     }
 
-    pub fn apply_forces_simd(&mut self) {
+    pub fn add_static_halo_forces(&mut self) {
+        for halo in &self.static_halos {
+            for i in 0..self.count {
+                let pos = Vector2::new(self.pos_x[i], self.pos_y[i]);
+                let acc = halo.acceleration(pos);
+                self.net_force_x[i] += acc.x * self.mass[i];
+                self.net_force_y[i] += acc.y * self.mass[i];
+            }
+        }
+    }
+
+    /// Advances velocities and then positions by `dt` (symplectic Euler).
+    pub fn apply_forces_simd(&mut self, dt: f32) {
         const LANES: usize = 8;
+        let dt_simd = Simd::<f32, LANES>::splat(dt);
         let mut i = 0;
         while i + LANES <= self.count {
             let mass = Simd::<f32, LANES>::from_slice(&self.mass[i..i + LANES]);
@@ -147,10 +199,10 @@ impl ParticleSystem {
             let acc_x = force_x / mass;
             let acc_y = force_y / mass;
 
-            vel_x += acc_x;
-            vel_y += acc_y;
-            pos_x += vel_x;
-            pos_y += vel_y;
+            vel_x += acc_x * dt_simd;
+            vel_y += acc_y * dt_simd;
+            pos_x += vel_x * dt_simd;
+            pos_y += vel_y * dt_simd;
 
             self.vel_x[i..i + LANES].copy_from_slice(&vel_x.to_array());
             self.vel_y[i..i + LANES].copy_from_slice(&vel_y.to_array());
@@ -163,10 +215,10 @@ impl ParticleSystem {
         for idx in i..self.count {
             let acc_x = self.net_force_x[idx] / self.mass[idx];
             let acc_y = self.net_force_y[idx] / self.mass[idx];
-            self.vel_x[idx] += acc_x;
-            self.vel_y[idx] += acc_y;
-            self.pos_x[idx] += self.vel_x[idx];
-            self.pos_y[idx] += self.vel_y[idx];
+            self.vel_x[idx] += acc_x * dt;
+            self.vel_y[idx] += acc_y * dt;
+            self.pos_x[idx] += self.vel_x[idx] * dt;
+            self.pos_y[idx] += self.vel_y[idx] * dt;
         }
     }
 
@@ -194,62 +246,85 @@ impl ParticleSystem {
         (self.vel_x[idx] * self.vel_x[idx] + self.vel_y[idx] * self.vel_y[idx]).sqrt()
     }
 
-    fn get_color(&self, value: f32, left: &Color, right: &Color) -> Color {
-        Color::from_rgb(
-            (((1.0 - value) * left.r + value * right.r) * 255.0) as u8,
-            (((1.0 - value) * left.g + value * right.g) * 255.0) as u8,
-            (((1.0 - value) * left.b + value * right.b) * 255.0) as u8,
-        )
+    /// The particle among `candidates` closest to `pos`, if any lies within `max_distance`.
+    pub fn nearest(
+        &self,
+        candidates: &[usize],
+        pos: Vector2<f32>,
+        max_distance: f32,
+    ) -> Option<usize> {
+        candidates
+            .iter()
+            .map(|&i| (i, self.get_distance_to(i, &pos)))
+            .filter(|&(_, d)| d <= max_distance)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
     }
 
-    pub fn show_particle(
-        &self,
-        idx: usize,
-        canvas: &mut Canvas,
-        ctx: &mut Context,
-        offset: Vector2<f32>,
-        zoom: f32,
-        max_vel: f32,
-        min_vel: f32,
-    ) {
-        let mut new_radius: f32;
-        if self.radius[idx] < 1.0 {
-            new_radius = 0.25 * zoom;
-        } else {
-            new_radius = self.radius[idx] * zoom;
+    /// Mass weighted mean position of all particles.
+    pub fn center_of_mass(&self) -> Vector2<f32> {
+        let mut weighted = Vector2::<f64>::zeros();
+        let mut total = 0.0f64;
+        for i in 0..self.count {
+            let mass = self.mass[i] as f64;
+            weighted += self.get_position(i).cast::<f64>() * mass;
+            total += mass;
         }
-        if new_radius < 0.25 {
-            new_radius = 0.25;
+        if total <= 0.0 {
+            return Vector2::zeros();
         }
+        (weighted / total).cast::<f32>()
+    }
 
-        let mid_vel = (max_vel + min_vel) / 2.0;
-        let left = Color::BLUE;
-        let middle = Color::GREEN;
-        let right = Color::RED;
-        let norm_vel = self.get_velocity_norm(idx);
-        let new_color: Color;
-
-        if norm_vel < min_vel + mid_vel {
-            new_color = self.get_color((norm_vel - min_vel) / mid_vel, &left, &right);
+    /// Radius on screen in pixels; small particles are drawn at a fixed minimum size.
+    pub fn screen_radius(&self, idx: usize, zoom: f32) -> f32 {
+        let radius = if self.radius[idx] < 1.0 {
+            0.25 * zoom
         } else {
-            new_color = self.get_color((norm_vel - min_vel - mid_vel) / mid_vel, &middle, &right);
-        }
+            self.radius[idx] * zoom
+        };
+        radius.max(0.25)
+    }
 
-        let particle_pos = self.get_position(idx);
-        let dot_mesh = graphics::Mesh::new_circle(
-            ctx,
-            graphics::DrawMode::fill(),
-            Point2 {
-                x: world_to_screen_coords(particle_pos, &offset, zoom).x,
-                y: world_to_screen_coords(particle_pos, &offset, zoom).y,
-            },
-            new_radius,
-            0.1,
-            new_color,
-        )
-        .unwrap();
+    /// Copies of the particles at `indices`, keeping their ids, colors and species.
+    pub fn subset(&self, indices: &[usize]) -> ParticleSystem {
+        let mut subset = ParticleSystem::with_capacity(indices.len());
+        for &i in indices {
+            subset.add_particle(
+                self.get_position(i),
+                self.get_velocity(i),
+                self.mass[i],
+                self.radius[i],
+                self.indices[i],
+            );
+            *subset.color.last_mut().unwrap() = self.color[i];
+            *subset.species.last_mut().unwrap() = self.species[i];
+        }
+        subset
+    }
 
-        canvas.draw(&dot_mesh, graphics::DrawParam::default());
+    /// Removes the particles at `indices`; the remaining ones keep their order.
+    pub fn remove(&mut self, indices: &[usize]) {
+        let mut keep = vec![true; self.count];
+        for &i in indices {
+            keep[i] = false;
+        }
+        fn retain<T>(column: &mut Vec<T>, keep: &[bool]) {
+            let mut flags = keep.iter();
+            column.retain(|_| *flags.next().unwrap());
+        }
+        retain(&mut self.pos_x, &keep);
+        retain(&mut self.pos_y, &keep);
+        retain(&mut self.vel_x, &keep);
+        retain(&mut self.vel_y, &keep);
+        retain(&mut self.net_force_x, &keep);
+        retain(&mut self.net_force_y, &keep);
+        retain(&mut self.mass, &keep);
+        retain(&mut self.radius, &keep);
+        retain(&mut self.indices, &keep);
+        retain(&mut self.color, &keep);
+        retain(&mut self.species, &keep);
+        self.count = self.mass.len();
     }
 
     pub fn sort_by_mass(&mut self) {
@@ -267,6 +342,8 @@ impl ParticleSystem {
         let mut new_mass = Vec::with_capacity(self.count);
         let mut new_radius = Vec::with_capacity(self.count);
         let mut new_indices = Vec::with_capacity(self.count);
+        let mut new_color = Vec::with_capacity(self.count);
+        let mut new_species = Vec::with_capacity(self.count);
 
         for &i in &indices {
             new_pos_x.push(self.pos_x[i]);
@@ -278,6 +355,8 @@ impl ParticleSystem {
             new_mass.push(self.mass[i]);
             new_radius.push(self.radius[i]);
             new_indices.push(self.indices[i]);
+            new_color.push(self.color[i]);
+            new_species.push(self.species[i]);
         }
 
         self.pos_x = new_pos_x;
@@ -289,53 +368,124 @@ impl ParticleSystem {
         self.mass = new_mass;
         self.radius = new_radius;
         self.indices = new_indices;
+        self.color = new_color;
+        self.species = new_species;
     }
+}
 
-    pub fn find_max_velocity_norm(&self) -> f32 {
-        use std::simd::{num::SimdFloat, Simd};
+/// Draws all particles as instances of one unit circle mesh, so a frame costs a single draw
+/// call instead of building a mesh per particle.
+pub struct ParticleBatch {
+    circle: Mesh,
+    instances: InstanceArray,
+}
 
-        let mut max_chunk = Simd::<f32, LANES>::splat(0.0);
-        let mut i = 0;
-        while i + LANES <= self.count {
-            let vx = Simd::<f32, LANES>::from_slice(&self.net_force_x[i..i + LANES]);
-            let vy = Simd::<f32, LANES>::from_slice(&self.net_force_y[i..i + LANES]);
-            let norm = (vx * vx + vy * vy).sqrt();
-            max_chunk = max_chunk.simd_max(norm);
-            i += LANES;
+impl ParticleBatch {
+    pub fn new(ctx: &Context) -> Self {
+        let circle = Mesh::new_circle(
+            ctx,
+            graphics::DrawMode::fill(),
+            [0.0, 0.0],
+            1.0,
+            0.01,
+            Color::WHITE,
+        )
+        .expect("Failed to create the particle mesh");
+        Self {
+            circle,
+            instances: InstanceArray::new(ctx, None),
         }
-        let mut max_vel = max_chunk.reduce_max();
-        for j in i..self.count {
-            let norm = self.get_velocity_norm(j);
-            if norm > max_vel {
-                max_vel = norm;
+    }
+
+    /// Draws `particle_list` with `colors` indexed like the particles.
+    pub fn draw(
+        &mut self,
+        canvas: &mut Canvas,
+        particles: &ParticleSystem,
+        particle_list: &[usize],
+        colors: &[Color],
+        offset: Vector2<f32>,
+        zoom: f32,
+    ) {
+        self.instances.set(particle_list.iter().map(|&i| {
+            let radius = particles.screen_radius(i, zoom);
+            let pos = world_to_screen_coords(particles.get_position(i), &offset, zoom);
+            DrawParam::default()
+                .dest([pos.x, pos.y])
+                .scale([radius, radius])
+                .color(colors[i])
+        }));
+        canvas.draw_instanced_mesh(self.circle.clone(), &self.instances, DrawParam::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Five particles whose every column encodes their row, with two species and some colors.
+    fn sample() -> ParticleSystem {
+        let mut particles = ParticleSystem::new();
+        for i in 0..5 {
+            if i == 2 {
+                particles.start_species();
+            }
+            let f = i as f32;
+            particles.add_particle(
+                Vector2::new(f, 10.0 + f),
+                Vector2::new(20.0 + f, 30.0 + f),
+                40.0 + f,
+                50.0 + f,
+                100 + i,
+            );
+            particles.net_force_x[i] = 60.0 + f;
+            particles.net_force_y[i] = 70.0 + f;
+            if i % 2 == 1 {
+                particles.color[i] = Some(Color::from_rgba(i as u8, 0, 0, 255));
             }
         }
-        max_vel
+        particles
     }
 
-    pub fn find_min_velocity_norm(&self) -> f32 {
-        use std::simd::{num::SimdFloat, Simd};
+    /// Checks that row `row` of `particles` holds row `original` of the sample in every column.
+    fn assert_row(particles: &ParticleSystem, row: usize, original: usize) {
+        let expected = sample();
+        assert_eq!(particles.pos_x[row], expected.pos_x[original]);
+        assert_eq!(particles.pos_y[row], expected.pos_y[original]);
+        assert_eq!(particles.vel_x[row], expected.vel_x[original]);
+        assert_eq!(particles.vel_y[row], expected.vel_y[original]);
+        assert_eq!(particles.mass[row], expected.mass[original]);
+        assert_eq!(particles.radius[row], expected.radius[original]);
+        assert_eq!(particles.indices[row], expected.indices[original]);
+        assert_eq!(particles.color[row], expected.color[original]);
+        assert_eq!(particles.species[row], expected.species[original]);
+    }
 
-        if self.count == 0 {
-            return 0.0;
+    #[test]
+    fn remove_keeps_the_columns_aligned() {
+        let mut particles = sample();
+        particles.remove(&[1, 3]);
+        assert_eq!(particles.count, 3);
+        for (row, original) in [0, 2, 4].into_iter().enumerate() {
+            assert_row(&particles, row, original);
+            assert_eq!(particles.net_force_x[row], 60.0 + original as f32);
+            assert_eq!(particles.net_force_y[row], 70.0 + original as f32);
         }
-        const LANES: usize = 8;
-        let mut min_chunk = Simd::<f32, LANES>::splat(f32::INFINITY);
-        let mut i = 0;
-        while i + LANES <= self.count {
-            let vx = Simd::<f32, LANES>::from_slice(&self.net_force_x[i..i + LANES]);
-            let vy = Simd::<f32, LANES>::from_slice(&self.net_force_y[i..i + LANES]);
-            let norm = (vx * vx + vy * vy).sqrt();
-            min_chunk = min_chunk.simd_min(norm);
-            i += LANES;
+        for column in [&particles.pos_x, &particles.vel_y, &particles.radius] {
+            assert_eq!(column.len(), 3);
         }
-        let mut min_vel = min_chunk.reduce_min();
-        for j in i..self.count {
-            let norm = self.get_velocity_norm(j);
-            if norm < min_vel {
-                min_vel = norm;
-            }
+        assert_eq!(particles.color.len(), 3);
+        assert_eq!(particles.species.len(), 3);
+    }
+
+    #[test]
+    fn subset_copies_the_rows_in_the_given_order() {
+        let particles = sample();
+        let subset = particles.subset(&[4, 0, 3]);
+        assert_eq!(subset.count, 3);
+        for (row, original) in [4, 0, 3].into_iter().enumerate() {
+            assert_row(&subset, row, original);
         }
-        min_vel
+        assert_eq!(subset.species, vec![1, 0, 1]);
     }
 }
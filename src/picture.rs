@@ -0,0 +1,135 @@
+use crate::particle::ParticleSystem;
+use ggez::graphics::Color;
+use image::ImageResult;
+use nalgebra::Vector2;
+use rand::Rng;
+use std::path::Path;
+
+/// Relative luminance of a pixel, scaled by its opacity.
+fn brightness(pixel: &image::Rgba<u8>) -> f64 {
+    let [r, g, b, a] = pixel.0.map(|c| c as f64 / 255.0);
+    (0.2126 * r + 0.7152 * g + 0.0722 * b) * a
+}
+
+/// Samples particles from the picture at `path`, with the density following the pixel
+/// brightness. The picture is centered on `center` and scaled to be `width` world units wide.
+///
+/// Every particle starts at rest, carries an equal share of `total_mass` and keeps the color of
+/// the pixel it was sampled from, so the picture stays recognizable while it collapses.
+pub fn create_from_image(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    path: &Path,
+    center: Vector2<f32>,
+    width: f32,
+    total_mass: f32,
+    particles_amount: i32,
+) -> ImageResult<()> {
+    let picture = image::open(path)?.to_rgba8();
+    let (columns, rows) = picture.dimensions();
+
+    let mut cumulative = Vec::with_capacity(picture.pixels().len());
+    let mut total = 0.0;
+    for pixel in picture.pixels() {
+        total += brightness(pixel);
+        cumulative.push(total);
+    }
+    if total <= 0.0 || particles_amount <= 0 {
+        return Ok(());
+    }
+
+    let scale = width / columns as f32;
+    let origin = center - Vector2::new(columns as f32, rows as f32) * scale / 2.0;
+    let particle_mass = total_mass / particles_amount as f32;
+    particles.start_species();
+    for i in 0..particles_amount {
+        let target = rng.gen_range(0.0..total);
+        let pixel_idx = cumulative
+            .partition_point(|&c| c <= target)
+            .min(cumulative.len() - 1);
+        let (column, row) = (pixel_idx as u32 % columns, pixel_idx as u32 / columns);
+
+        // Spread the particles over the pixel's area instead of stacking them on its corner.
+        let pos = origin
+            + Vector2::new(
+                column as f32 + rng.gen_range(0.0..1.0),
+                row as f32 + rng.gen_range(0.0..1.0),
+            ) * scale;
+        particles.add_particle(pos, Vector2::zeros(), particle_mass, 0.001, i as usize);
+
+        let [r, g, b, _] = picture.get_pixel(column, row).0;
+        *particles.color.last_mut().unwrap() = Some(Color::from_rgb(r, g, b));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn particles_follow_the_pixel_brightness() {
+        let pixels = [
+            [255, 255, 255, 255],
+            [0, 0, 0, 255],
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [255, 255, 255, 0],
+            [255, 255, 255, 128],
+            [0, 0, 255, 255],
+            [128, 128, 128, 255],
+        ];
+        let (columns, rows) = (4, 2);
+        let picture = image::RgbaImage::from_fn(columns, rows, |x, y| {
+            image::Rgba(pixels[(y * columns + x) as usize])
+        });
+        let path = std::env::temp_dir().join(format!("gp_picture_{}_cdf.png", std::process::id()));
+        picture.save(&path).unwrap();
+
+        let amount = 40000;
+        let mut particles = ParticleSystem::new();
+        let mut rng = StdRng::seed_from_u64(5);
+        // One world unit per pixel, with the picture's corner at the origin.
+        let center = Vector2::new(columns as f32, rows as f32) / 2.0;
+        create_from_image(
+            &mut particles,
+            &mut rng,
+            &path,
+            center,
+            columns as f32,
+            10.0,
+            amount,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(particles.count, amount as usize);
+
+        let mut counts = [0; 8];
+        for i in 0..particles.count {
+            let pos = particles.get_position(i);
+            let idx = (pos.y as u32 * columns + pos.x as u32) as usize;
+            counts[idx] += 1;
+            let [r, g, b, _] = pixels[idx];
+            assert_eq!(particles.color[i], Some(Color::from_rgb(r, g, b)));
+        }
+
+        let brightness: Vec<f64> = picture.pixels().map(brightness).collect();
+        let total: f64 = brightness.iter().sum();
+        for (idx, (&count, &b)) in counts.iter().zip(&brightness).enumerate() {
+            let expected = amount as f64 * b / total;
+            // Four standard deviations of the binomial count.
+            let tolerance = 4.0 * expected.sqrt() + 1e-9;
+            assert!(
+                (count as f64 - expected).abs() <= tolerance,
+                "pixel {}: {} particles, expected {}",
+                idx,
+                count,
+                expected
+            );
+        }
+        assert_eq!(counts[1], 0);
+        assert_eq!(counts[4], 0);
+    }
+}
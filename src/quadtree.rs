@@ -1,11 +1,117 @@
+use crate::coloring::Colormap;
 use crate::particle::ParticleSystem;
 use crate::rectangle::Rectangle;
 use ggez::{
-    graphics::{Canvas, Color},
+    graphics::{Canvas, Color, DrawMode, DrawParam, Mesh, MeshBuilder},
     Context,
 };
 use nalgebra::Vector2;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::simd::{cmp::SimdPartialOrd, *};
+
+/// Opening angle of the Barnes-Hut walk: nodes narrower than this fraction of their distance
+/// are treated as a single mass.
+const THETA: f32 = 0.5;
+
+/// Shape statistics of a tree, mostly useful for spotting degenerate trees.
+#[derive(Debug, Default)]
+pub struct TreeStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Leaves holding a particle; every particle inside the bounds ends up in exactly one.
+    pub occupied_leaves: usize,
+    pub max_depth: usize,
+    /// Number of occupied leaves at each depth.
+    pub occupied_per_depth: Vec<usize>,
+}
+
+impl TreeStats {
+    pub fn leaf_occupancy(&self) -> f32 {
+        if self.leaf_count == 0 {
+            return 0.0;
+        }
+        self.occupied_leaves as f32 / self.leaf_count as f32
+    }
+
+    pub fn mean_leaf_depth(&self) -> f32 {
+        if self.occupied_leaves == 0 {
+            return 0.0;
+        }
+        let total: usize = self
+            .occupied_per_depth
+            .iter()
+            .enumerate()
+            .map(|(depth, &n)| depth * n)
+            .sum();
+        total as f32 / self.occupied_leaves as f32
+    }
+}
+
+impl std::fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "nodes: {}, leaves: {} ({} occupied, {:.1}%), max depth: {}, mean particle depth: {:.2}",
+            self.node_count,
+            self.leaf_count,
+            self.occupied_leaves,
+            self.leaf_occupancy() * 100.0,
+            self.max_depth,
+            self.mean_leaf_depth()
+        )
+    }
+}
+/// What the drawn node bounds are colored by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoundsColoring {
+    Depth,
+    /// Node mass relative to the whole tree, on a log scale.
+    Mass,
+}
+
+/// Settings for drawing the node bounds on top of the particles.
+pub struct TreeView {
+    pub enabled: bool,
+    /// Deepest level drawn, all levels when `None`.
+    pub max_depth: Option<usize>,
+    pub coloring: BoundsColoring,
+}
+
+impl TreeView {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            max_depth: None,
+            coloring: BoundsColoring::Depth,
+        }
+    }
+
+    pub fn next_coloring(&mut self) {
+        self.coloring = match self.coloring {
+            BoundsColoring::Depth => BoundsColoring::Mass,
+            BoundsColoring::Mass => BoundsColoring::Depth,
+        };
+    }
+
+    /// Draws one level less, starting from the deepest level of `tree_depth`.
+    pub fn shallower(&mut self, tree_depth: usize) {
+        self.max_depth = Some(self.max_depth.unwrap_or(tree_depth).saturating_sub(1));
+    }
+
+    /// Draws one level more, back to all levels once `tree_depth` is reached.
+    pub fn deeper(&mut self, tree_depth: usize) {
+        self.max_depth = self
+            .max_depth
+            .map(|depth| depth + 1)
+            .filter(|&depth| depth < tree_depth);
+    }
+}
+
+/// Orders of magnitude of node mass spanned by the colormap in mass coloring.
+const MASS_DECADES: f32 = 6.0;
+
 #[derive(Clone)]
 pub struct QuadTree {
     bounds: Rectangle,
@@ -89,12 +195,10 @@ impl QuadTree {
         }
 
         // insert new into appropriate child
-        for child_opt in &mut self.children {
-            if let Some(child) = child_opt {
-                if child.bounds.contains_point(&pos) {
-                    child.insert(particles, idx);
-                    break;
-                }
+        for child in self.children.iter_mut().flatten() {
+            if child.bounds.contains_point(&pos) {
+                child.insert(particles, idx);
+                break;
             }
         }
 
@@ -123,49 +227,280 @@ impl QuadTree {
             }
         }
         if !self.is_leaf() {
-            for child_opt in &self.children {
-                if let Some(child) = child_opt {
-                    child.query_recursive(area, particles, out);
-                }
+            for child in self.children.iter().flatten() {
+                child.query_recursive(area, particles, out);
             }
         }
     }
 
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        self.collect_stats(0, &mut stats);
+        stats
+    }
+
+    fn collect_stats(&self, depth: usize, stats: &mut TreeStats) {
+        stats.node_count += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+        if !self.is_leaf() {
+            for child in self.children.iter().flatten() {
+                child.collect_stats(depth + 1, stats);
+            }
+            return;
+        }
+        stats.leaf_count += 1;
+        if self.particle_idx.is_some() {
+            stats.occupied_leaves += 1;
+            if stats.occupied_per_depth.len() <= depth {
+                stats.occupied_per_depth.resize(depth + 1, 0);
+            }
+            stats.occupied_per_depth[depth] += 1;
+        }
+    }
+
+    /// Stores the area of the leaf holding each particle at the particle's index in `areas`,
+    /// which gives a local density estimate since every leaf holds a single particle.
+    pub fn leaf_areas(&self, areas: &mut [f32]) {
+        if !self.is_leaf() {
+            for child in self.children.iter().flatten() {
+                child.leaf_areas(areas);
+            }
+        } else if let Some(idx) = self.particle_idx {
+            if let Some(area) = areas.get_mut(idx) {
+                *area = self.bounds.w * self.bounds.h;
+            }
+        }
+    }
+
+    /// Writes the tree as nested JSON nodes with bounds, depth, mass, center of mass and the
+    /// index of the stored particle, preceded by the tree statistics.
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        let stats = self.stats();
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{{")?;
+        writeln!(
+            out,
+            "  \"stats\": {{\"node_count\": {}, \"leaf_count\": {}, \"occupied_leaves\": {}, \
+             \"leaf_occupancy\": {}, \"max_depth\": {}, \"occupied_per_depth\": {:?}}},",
+            stats.node_count,
+            stats.leaf_count,
+            stats.occupied_leaves,
+            stats.leaf_occupancy(),
+            stats.max_depth,
+            stats.occupied_per_depth
+        )?;
+        write!(out, "  \"root\": ")?;
+        self.write_json_node(&mut out, 0)?;
+        writeln!(out, "\n}}")?;
+        out.flush()
+    }
+
+    fn write_json_node(&self, out: &mut impl Write, depth: usize) -> io::Result<()> {
+        let Rectangle { top_left_pos, w, h } = &self.bounds;
+        let particle = match self.particle_idx {
+            Some(idx) => idx.to_string(),
+            None => "null".to_string(),
+        };
+        write!(
+            out,
+            "{{\"bounds\": [{}, {}, {}, {}], \"depth\": {}, \"mass\": {}, \
+             \"center_of_mass\": [{}, {}], \"particle\": {}, \"children\": [",
+            top_left_pos.x,
+            top_left_pos.y,
+            w,
+            h,
+            depth,
+            self.mass,
+            self.center_of_mass.x,
+            self.center_of_mass.y,
+            particle
+        )?;
+        for (i, child) in self.children.iter().flatten().enumerate() {
+            if i > 0 {
+                write!(out, ", ")?;
+            }
+            child.write_json_node(out, depth + 1)?;
+        }
+        write!(out, "]}}")
+    }
+
+    /// Writes the tree as a GraphViz DOT digraph, one box per node.
+    pub fn write_dot(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "digraph quadtree {{")?;
+        writeln!(out, "  node [shape=box, fontsize=10];")?;
+        let mut next_id = 0;
+        self.write_dot_node(&mut out, 0, &mut next_id)?;
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+
+    fn write_dot_node(
+        &self,
+        out: &mut impl Write,
+        depth: usize,
+        next_id: &mut usize,
+    ) -> io::Result<usize> {
+        let id = *next_id;
+        *next_id += 1;
+        let Rectangle { top_left_pos, w, h } = &self.bounds;
+        let mut label = format!(
+            "depth {}\\n[{:.1}, {:.1}] {:.1}x{:.1}\\nmass {:.4}\\ncom ({:.1}, {:.1})",
+            depth,
+            top_left_pos.x,
+            top_left_pos.y,
+            w,
+            h,
+            self.mass,
+            self.center_of_mass.x,
+            self.center_of_mass.y
+        );
+        if let Some(idx) = self.particle_idx {
+            label.push_str(&format!("\\nparticle {}", idx));
+        }
+        let style = if self.particle_idx.is_some() {
+            ", style=filled, fillcolor=lightblue"
+        } else {
+            ""
+        };
+        writeln!(out, "  n{} [label=\"{}\"{}];", id, label, style)?;
+        for child in self.children.iter().flatten() {
+            let child_id = child.write_dot_node(out, depth + 1, next_id)?;
+            writeln!(out, "  n{} -> n{};", id, child_id)?;
+        }
+        Ok(id)
+    }
+
+    fn collect_bounds(
+        &self,
+        depth: usize,
+        max_depth: Option<usize>,
+        out: &mut Vec<(Rectangle, usize, f32)>,
+    ) {
+        if max_depth.is_some_and(|max| depth > max) {
+            return;
+        }
+        out.push((self.bounds.clone(), depth, self.mass));
+        for child in self.children.iter().flatten() {
+            child.collect_bounds(depth + 1, max_depth, out);
+        }
+    }
+
+    /// Draws the bounds of every node down to the view's depth limit as a single mesh.
     pub fn show(
         &self,
         canvas: &mut Canvas,
         ctx: &mut Context,
         offset: Vector2<f32>,
         zoom: f32,
-        particle_list: &[usize],
-        particles: &ParticleSystem,
-        max_vel: f32,
-        min_vel: f32,
-        draw_bounds: bool,
+        view: &TreeView,
+        colormap: Colormap,
     ) {
-        if draw_bounds {
-            self.bounds.show(canvas, ctx, offset, zoom, Color::MAGENTA);
-        }
-        for &i in particle_list {
-            particles.show_particle(i, canvas, ctx, offset, zoom, max_vel, min_vel);
-        }
-        if draw_bounds && !self.is_leaf() {
-            for child_opt in &self.children {
-                if let Some(child) = child_opt {
-                    child.show(
-                        canvas,
-                        ctx,
-                        offset,
-                        zoom,
-                        particle_list,
-                        particles,
-                        max_vel,
-                        min_vel,
-                        draw_bounds,
-                    );
+        if !view.enabled {
+            return;
+        }
+        let mut nodes = Vec::new();
+        self.collect_bounds(0, view.max_depth, &mut nodes);
+        let deepest = nodes.iter().map(|&(_, depth, _)| depth).max().unwrap_or(0);
+
+        let mut builder = MeshBuilder::new();
+        for (bounds, depth, mass) in &nodes {
+            let t = match view.coloring {
+                BoundsColoring::Depth => *depth as f32 / deepest.max(1) as f32,
+                BoundsColoring::Mass if *mass > 0.0 && self.mass > 0.0 => {
+                    1.0 + (mass / self.mass).log10() / MASS_DECADES
                 }
+                BoundsColoring::Mass => 0.0,
+            };
+            let mut color = colormap.sample(t);
+            color.a = 0.5;
+            let _ = builder.rectangle(DrawMode::stroke(1.0), bounds.to_screen(offset, zoom), color);
+        }
+        canvas.draw(&Mesh::from_data(ctx, builder.build()), DrawParam::default());
+    }
+
+    /// Collects the nodes the force walk for particle `idx` looks at: `opened` nodes were too
+    /// close and got descended into, `accepted` ones acted on the particle as a single mass.
+    pub fn force_walk(
+        &self,
+        particles: &ParticleSystem,
+        idx: usize,
+        opened: &mut Vec<Rectangle>,
+        accepted: &mut Vec<Rectangle>,
+    ) {
+        let d = self.center_of_mass - particles.get_position(idx);
+        let dist = (d.norm_squared() + crate::consts::SOFTENING.powi(2)).sqrt();
+        if self.bounds.w / dist < THETA {
+            if self.mass > 0.0 {
+                accepted.push(self.bounds.clone());
             }
+            return;
+        }
+        if self.is_leaf() {
+            if self.particle_idx.is_some_and(|other_idx| other_idx != idx) {
+                accepted.push(self.bounds.clone());
+            }
+            return;
+        }
+        opened.push(self.bounds.clone());
+        for child in self.children.iter().flatten() {
+            child.force_walk(particles, idx, opened, accepted);
+        }
+    }
+
+    /// Highlights the nodes of the force walk for particle `idx` and marks the particle.
+    pub fn show_force_walk(
+        &self,
+        canvas: &mut Canvas,
+        ctx: &mut Context,
+        particles: &ParticleSystem,
+        idx: usize,
+        offset: Vector2<f32>,
+        zoom: f32,
+    ) {
+        let (mut opened, mut accepted) = (Vec::new(), Vec::new());
+        self.force_walk(particles, idx, &mut opened, &mut accepted);
+
+        let mut builder = MeshBuilder::new();
+        for bounds in &accepted {
+            let rect = bounds.to_screen(offset, zoom);
+            let _ = builder.rectangle(DrawMode::fill(), rect, Color::new(0.0, 1.0, 1.0, 0.15));
+            let _ = builder.rectangle(DrawMode::stroke(1.0), rect, Color::new(0.0, 1.0, 1.0, 0.6));
         }
+        for bounds in &opened {
+            let rect = bounds.to_screen(offset, zoom);
+            let _ = builder.rectangle(DrawMode::stroke(2.0), rect, Color::YELLOW);
+        }
+        let pos = crate::utils::world_to_screen_coords(particles.get_position(idx), &offset, zoom);
+        let _ = builder.circle(
+            DrawMode::stroke(2.0),
+            [pos.x, pos.y],
+            particles.screen_radius(idx, zoom) + 6.0,
+            0.5,
+            Color::WHITE,
+        );
+        canvas.draw(&Mesh::from_data(ctx, builder.build()), DrawParam::default());
+    }
+
+    /// Potential energy per unit mass at particle `idx` from all other particles, using the
+    /// same approximation as the force walk.
+    pub fn potential_at(&self, particles: &ParticleSystem, idx: usize) -> f32 {
+        let d = self.center_of_mass - particles.get_position(idx);
+        let dist = (d.norm_squared() + crate::consts::SOFTENING.powi(2)).sqrt();
+        if self.is_leaf() {
+            return match self.particle_idx {
+                Some(other_idx) if other_idx != idx => -crate::consts::G * self.mass / dist,
+                _ => 0.0,
+            };
+        }
+        if self.bounds.w / dist < THETA {
+            return -crate::consts::G * self.mass / dist;
+        }
+        self.children
+            .iter()
+            .flatten()
+            .map(|child| child.potential_at(particles, idx))
+            .sum()
     }
 
     pub fn calculate_force_simd(&self, particles: &mut ParticleSystem, indices: &[usize]) {
@@ -189,7 +524,7 @@ impl QuadTree {
             let dist = dist_sq.sqrt();
             let ratio = Simd::<f32, LANES>::splat(width) / dist;
 
-            let mask = ratio.simd_lt(Simd::splat(0.5));
+            let mask = ratio.simd_lt(Simd::splat(THETA));
 
             let inv = Simd::<f32, LANES>::splat(1.0) / dist;
             let dir_x = dx * inv;
@@ -212,8 +547,19 @@ impl QuadTree {
             i += LANES;
         }
 
+        // The particles that don't fill a whole chunk get the same treatment one at a time.
         for &idx in &indices[i..] {
-            remainder.push(idx);
+            let dx = center_x - particles.pos_x[idx];
+            let dy = center_y - particles.pos_y[idx];
+            let dist_sq = dx * dx + dy * dy + soft_sq;
+            let dist = dist_sq.sqrt();
+            if width / dist < THETA {
+                let magnitude = crate::consts::G * self.mass * particles.mass[idx] / dist_sq;
+                particles.net_force_x[idx] += dx / dist * magnitude;
+                particles.net_force_y[idx] += dy / dist * magnitude;
+            } else {
+                remainder.push(idx);
+            }
         }
 
         if remainder.is_empty() {
@@ -229,11 +575,56 @@ impl QuadTree {
                 }
             }
         } else {
-            for child_opt in &self.children {
-                if let Some(child) = child_opt {
-                    child.calculate_force_simd(particles, &remainder);
-                }
+            for child in self.children.iter().flatten() {
+                child.calculate_force_simd(particles, &remainder);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::particle::ParticleSystem;
+    use crate::utils::create_quadtree;
+    use nalgebra::Vector2;
+
+    /// Two clumps apart from each other, so the walk treats parts of the far one as single masses.
+    fn clumps(amount: usize) -> ParticleSystem {
+        let mut particles = ParticleSystem::with_capacity(amount);
+        for i in 0..amount {
+            let center = if i % 2 == 0 { 200.0 } else { 800.0 };
+            let angle = i as f32 * 2.4;
+            let r = 1.0 + 0.5 * i as f32;
+            let pos = Vector2::new(center + r * angle.cos(), center + r * angle.sin());
+            particles.add_particle(pos, Vector2::zeros(), 1.0 + i as f32 * 0.1, 0.001, i);
+        }
+        particles
+    }
+
+    #[test]
+    fn force_does_not_depend_on_the_simd_chunk() {
+        // 21 particles leave a remainder of 5 after the chunks of 8.
+        let mut particles = clumps(21);
+        let qt = create_quadtree(&particles);
+        let all: Vec<usize> = (0..particles.count).collect();
+        qt.calculate_force_simd(&mut particles, &all);
+        let together: Vec<(f32, f32)> = (0..particles.count)
+            .map(|i| (particles.net_force_x[i], particles.net_force_y[i]))
+            .collect();
+
+        for (i, &(fx, fy)) in together.iter().enumerate() {
+            particles.net_force_x[i] = 0.0;
+            particles.net_force_y[i] = 0.0;
+            qt.calculate_force_simd(&mut particles, &[i]);
+            let tolerance = 1e-4 * fx.hypot(fy);
+            assert!(
+                (particles.net_force_x[i] - fx).abs() <= tolerance
+                    && (particles.net_force_y[i] - fy).abs() <= tolerance,
+                "particle {}: {:?} alone, {:?} in a chunk",
+                i,
+                (particles.net_force_x[i], particles.net_force_y[i]),
+                (fx, fy)
+            );
+        }
+    }
+}
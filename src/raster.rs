@@ -0,0 +1,179 @@
+use crate::consts::{HEIGHT, WIDTH};
+use crate::particle::ParticleSystem;
+use crate::utils::world_to_screen_coords;
+use chrono::{DateTime, Local};
+use ggez::graphics::Color;
+use image::{Rgba, RgbaImage};
+use nalgebra::Vector2;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Creates a fresh `results/frames_<timestamp>_seed<seed>` folder for rendered frames.
+pub fn create_frames_dir(seed: u64) -> io::Result<PathBuf> {
+    let now: DateTime<Local> = Local::now();
+    let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
+    let dir = std::env::current_dir()?
+        .join("results")
+        .join(format!("frames_{}_seed{}", timestamp, seed));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Window pixels per frame pixel and the frame position of the window's top left corner, so
+/// the whole window view fits into a `width` x `height` frame, centered along the longer side.
+pub fn fit_window(width: u32, height: u32) -> (f32, Vector2<f32>) {
+    let scale = (width as f32 / WIDTH).min(height as f32 / HEIGHT);
+    let offset = Vector2::new(
+        (width as f32 - WIDTH * scale) / 2.0,
+        (height as f32 - HEIGHT * scale) / 2.0,
+    );
+    (scale, offset)
+}
+
+/// Software counterpart of the window's particle drawing for runs without a GPU or display.
+///
+/// It uses the window's camera, so a frame shows what the window would show at the same origin
+/// and zoom, scaled to fit the frame. Overlapping particles add up their colors.
+pub struct Rasterizer {
+    width: u32,
+    height: u32,
+    /// Frame pixels per screen pixel of the window.
+    scale: f32,
+    /// Frame position of the window's top left corner.
+    offset: Vector2<f32>,
+    accum: Vec<[f32; 3]>,
+    /// Scratch space for the coverage of one disc.
+    weights: Vec<(usize, f32)>,
+}
+
+impl Rasterizer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let (scale, offset) = fit_window(width, height);
+        Self {
+            width,
+            height,
+            scale,
+            offset,
+            accum: vec![[0.0; 3]; (width * height) as usize],
+            weights: Vec::new(),
+        }
+    }
+
+    /// Adds a disc with antialiased edges to the accumulation buffer. The coverage is normalized
+    /// to the disc's area, so discs smaller than a pixel spread their area over the covered
+    /// pixels and every disc adds the same light wherever it sits on the pixel grid.
+    fn splat(&mut self, center: Vector2<f32>, radius: f32, color: Color) {
+        // Reaching past half a pixel diagonal always covers at least one pixel center.
+        let reach = radius.max(0.25) + 0.5;
+        let (x0, x1) = (
+            (center.x - reach).floor() as i64,
+            (center.x + reach).ceil() as i64,
+        );
+        let (y0, y1) = (
+            (center.y - reach).floor() as i64,
+            (center.y + reach).ceil() as i64,
+        );
+        if x1 < 0 || y1 < 0 || x0 >= self.width as i64 || y0 >= self.height as i64 {
+            return;
+        }
+        self.weights.clear();
+        let mut total = 0.0;
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let d = Vector2::new(x as f32 + 0.5, y as f32 + 0.5).metric_distance(&center);
+                let coverage = (reach - d).clamp(0.0, 1.0);
+                if coverage <= 0.0 {
+                    continue;
+                }
+                // Pixels outside the frame still count, the light there is not shown.
+                total += coverage;
+                if x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64 {
+                    self.weights
+                        .push(((y as u32 * self.width + x as u32) as usize, coverage));
+                }
+            }
+        }
+        if total <= 0.0 {
+            return;
+        }
+        let intensity = std::f32::consts::PI * radius * radius / total;
+        for &(idx, coverage) in &self.weights {
+            let pixel = &mut self.accum[idx];
+            pixel[0] += color.r * coverage * intensity;
+            pixel[1] += color.g * coverage * intensity;
+            pixel[2] += color.b * coverage * intensity;
+        }
+    }
+
+    /// Renders all particles with `colors` indexed like the particles, on a black background.
+    pub fn render(
+        &mut self,
+        particles: &ParticleSystem,
+        colors: &[Color],
+        origin: Vector2<f32>,
+        zoom: f32,
+    ) -> RgbaImage {
+        self.accum.fill([0.0; 3]);
+        for (i, &color) in colors.iter().enumerate().take(particles.count) {
+            let pos = world_to_screen_coords(particles.get_position(i), &origin, zoom) * self.scale
+                + self.offset;
+            let radius = particles.screen_radius(i, zoom) * self.scale;
+            self.splat(pos, radius, color);
+        }
+
+        let to_byte = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let mut frame = RgbaImage::new(self.width, self.height);
+        for (pixel, value) in frame.pixels_mut().zip(&self.accum) {
+            *pixel = Rgba([to_byte(value[0]), to_byte(value[1]), to_byte(value[2]), 255]);
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn total_light(rasterizer: &Rasterizer) -> [f32; 3] {
+        rasterizer.accum.iter().fold([0.0; 3], |sum, pixel| {
+            [sum[0] + pixel[0], sum[1] + pixel[1], sum[2] + pixel[2]]
+        })
+    }
+
+    #[test]
+    fn splat_adds_the_disc_area_in_color() {
+        let color = Color::new(1.0, 0.5, 0.25, 1.0);
+        for radius in [0.1, 0.4, 1.0, 3.0, 10.0] {
+            for center in [Vector2::new(32.0, 32.0), Vector2::new(20.3, 41.7)] {
+                let mut rasterizer = Rasterizer::new(64, 64);
+                rasterizer.splat(center, radius, color);
+                let area = PI * radius * radius;
+                let total = total_light(&rasterizer);
+                for (channel, value) in [color.r, color.g, color.b].iter().enumerate() {
+                    assert!(
+                        (total[channel] / (value * area) - 1.0).abs() < 1e-4,
+                        "radius {} at {:?}: {} vs {}",
+                        radius,
+                        center,
+                        total[channel],
+                        value * area
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn splat_drops_the_light_outside_the_frame() {
+        let mut rasterizer = Rasterizer::new(64, 64);
+        rasterizer.splat(Vector2::new(0.0, 32.0), 5.0, Color::WHITE);
+        let half = 0.5 * PI * 25.0;
+        assert!((total_light(&rasterizer)[0] / half - 1.0).abs() < 0.1);
+
+        let mut rasterizer = Rasterizer::new(64, 64);
+        rasterizer.splat(Vector2::new(-10.0, 32.0), 5.0, Color::WHITE);
+        assert_eq!(total_light(&rasterizer), [0.0; 3]);
+    }
+}
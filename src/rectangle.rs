@@ -33,6 +33,13 @@ impl Rectangle {
         !(up || down || left || right)
     }
 
+    /// The rectangle in screen pixels.
+    pub fn to_screen(&self, offset: Vector2<f32>, zoom: f32) -> graphics::Rect {
+        let top_left = world_to_screen_coords(self.top_left_pos, &offset, zoom);
+        graphics::Rect::new(top_left.x, top_left.y, self.w * zoom, self.h * zoom)
+    }
+
+    #[allow(dead_code)]
     pub fn show(
         &self,
         canvas: &mut Canvas,
@@ -42,12 +49,7 @@ impl Rectangle {
         mut color: Color,
     ) {
         color.a = 0.3;
-        let rect = graphics::Rect {
-            x: world_to_screen_coords(self.top_left_pos, &offset, zoom).x,
-            y: world_to_screen_coords(self.top_left_pos, &offset, zoom).y,
-            w: self.w * zoom,
-            h: self.h * zoom,
-        };
+        let rect = self.to_screen(offset, zoom);
         let rect_mesh = graphics::Mesh::new_rectangle(
             ctx,
             graphics::DrawMode::Stroke(graphics::StrokeOptions::DEFAULT),
@@ -0,0 +1,205 @@
+use crate::particle::ParticleSystem;
+use crate::quadtree::QuadTree;
+use crate::utils::create_quadtree;
+
+/// Kinetic and potential energy of the whole system.
+#[derive(Clone, Copy, Debug)]
+pub struct Energy {
+    pub kinetic: f32,
+    pub potential: f32,
+}
+
+impl Energy {
+    pub fn total(&self) -> f32 {
+        self.kinetic + self.potential
+    }
+}
+
+/// Where a simulation is in time, recorded with snapshots and trajectory frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Clock {
+    pub steps: u64,
+    pub time: f64,
+    pub dt: f32,
+}
+
+/// Physics state shared by the windowed and headless runners.
+pub struct Simulation {
+    pub particles: ParticleSystem,
+    /// Tree of the current positions, rebuilt after every step and every edit of the particles.
+    pub qt: QuadTree,
+    force_idxs: Vec<usize>,
+    pub steps: u64,
+    /// Time step of the next step; the scenes are set up for 1.
+    pub dt: f32,
+    /// Simulated time, the sum of all time steps taken.
+    pub time: f64,
+}
+
+impl Simulation {
+    pub fn new(particles: ParticleSystem) -> Self {
+        let qt = create_quadtree(&particles);
+        let force_idxs = Vec::with_capacity(particles.count);
+        Self {
+            particles,
+            qt,
+            force_idxs,
+            steps: 0,
+            dt: 1.0,
+            time: 0.0,
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        Clock {
+            steps: self.steps,
+            time: self.time,
+            dt: self.dt,
+        }
+    }
+
+    /// Replaces the state with a recorded one, e.g. when replaying a trajectory.
+    pub fn load_state(&mut self, particles: ParticleSystem, clock: Clock) {
+        self.qt = create_quadtree(&particles);
+        self.particles = particles;
+        self.steps = clock.steps;
+        self.time = clock.time;
+        self.dt = clock.dt;
+    }
+
+    /// Rebuilds the tree after particles were added, removed or reweighed between steps.
+    pub fn rebuild_tree(&mut self) {
+        self.qt = create_quadtree(&self.particles);
+    }
+
+    /// Measures the energy with the tree approximation, including the static halos.
+    pub fn energy(&self) -> Energy {
+        let mut kinetic = 0.0f64;
+        let mut potential = 0.0f64;
+        for i in 0..self.particles.count {
+            let mass = self.particles.mass[i] as f64;
+            kinetic += 0.5 * mass * (self.particles.get_velocity_norm(i) as f64).powi(2);
+            // Every pair is visited from both sides.
+            potential += 0.5 * mass * self.qt.potential_at(&self.particles, i) as f64;
+            let pos = self.particles.get_position(i);
+            for halo in &self.particles.static_halos {
+                potential += mass * halo.potential(pos) as f64;
+            }
+        }
+        Energy {
+            kinetic: kinetic as f32,
+            potential: potential as f32,
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.particles.reset_all_net_force();
+        // Reuse the same index buffer to avoid allocating every frame.
+        self.force_idxs.resize(self.particles.count, 0);
+        for (i, slot) in self.force_idxs.iter_mut().enumerate() {
+            *slot = i;
+        }
+        self.qt
+            .calculate_force_simd(&mut self.particles, &self.force_idxs);
+        self.particles.add_static_halo_forces();
+        self.particles.apply_forces_simd(self.dt);
+        // Rebuild the quadtree in-place instead of allocating a new holder each frame.
+        self.qt = create_quadtree(&self.particles);
+        self.steps += 1;
+        self.time += self.dt as f64;
+    }
+}
+
+/// Pause, single stepping and speed of a live simulation in the GUI.
+pub struct TimeControl {
+    pub paused: bool,
+    /// Simulated time per frame in units of the base time step. Speeds above one take
+    /// several steps per frame, speeds below one take a single shorter step.
+    pub speed: f32,
+    base_dt: f32,
+    /// Steps requested while paused, taken on the next frame.
+    pending_steps: u64,
+}
+
+impl TimeControl {
+    pub fn new(base_dt: f32) -> Self {
+        Self {
+            paused: false,
+            speed: 1.0,
+            base_dt,
+            pending_steps: 0,
+        }
+    }
+
+    /// Takes `steps` steps and pauses, used for single stepping.
+    pub fn step_by(&mut self, steps: u64) {
+        self.paused = true;
+        self.pending_steps += steps;
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(64.0);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(1.0 / 64.0);
+    }
+
+    fn dt(&self) -> f32 {
+        self.base_dt * self.speed.min(1.0)
+    }
+
+    /// Number of steps to take this frame and their time step.
+    pub fn frame_steps(&mut self) -> (u64, f32) {
+        if self.paused {
+            return (std::mem::take(&mut self.pending_steps), self.dt());
+        }
+        (self.speed.max(1.0) as u64, self.dt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_state_restores_the_recorded_clock() {
+        let mut sim = Simulation::new(ParticleSystem::new());
+        let clock = Clock {
+            steps: 40,
+            time: 12.5,
+            dt: 0.25,
+        };
+        sim.load_state(ParticleSystem::new(), clock);
+        assert_eq!(sim.clock(), clock);
+    }
+
+    #[test]
+    fn frame_steps_follow_the_speed() {
+        let mut control = TimeControl::new(1.0);
+        assert_eq!(control.frame_steps(), (1, 1.0));
+        control.faster();
+        control.faster();
+        assert_eq!(control.frame_steps(), (4, 1.0));
+        for _ in 0..3 {
+            control.slower();
+        }
+        assert_eq!(control.frame_steps(), (1, 0.5));
+        for _ in 0..20 {
+            control.slower();
+        }
+        assert_eq!(control.frame_steps(), (1, 1.0 / 64.0));
+    }
+
+    #[test]
+    fn step_by_pauses_and_takes_the_steps_once() {
+        let mut control = TimeControl::new(0.5);
+        control.step_by(1);
+        control.step_by(2);
+        assert!(control.paused);
+        assert_eq!(control.frame_steps(), (3, 0.5));
+        assert_eq!(control.frame_steps(), (0, 0.5));
+        control.paused = false;
+        assert_eq!(control.frame_steps(), (1, 0.5));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::particle::ParticleSystem;
+use crate::utils::{create_galaxy, spawn_circle, world_to_screen_coords};
+use ggez::graphics::{Canvas, Color, DrawParam, Mesh, MeshBuilder};
+use ggez::Context;
+use nalgebra::Vector2;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Initial velocity per world unit of mouse drag.
+const VELOCITY_PER_UNIT: f32 = 0.002;
+
+/// What a middle click drops at the cursor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpawnKind {
+    Particle,
+    /// A `create_galaxy` disk around a sun.
+    Galaxy,
+    /// A `spawn_circle` cluster of particles at rest.
+    Cluster,
+    /// The last saved or loaded particle group, with its center of mass at the cursor.
+    Group,
+}
+
+/// Drops particles where the middle mouse button is released, moving with the velocity given
+/// by dragging from the press position.
+pub struct Spawner {
+    /// World position where the drag started.
+    start: Option<Vector2<f32>>,
+    rng: StdRng,
+    group: Option<ParticleSystem>,
+}
+
+impl Spawner {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            start: None,
+            rng: StdRng::seed_from_u64(seed),
+            group: None,
+        }
+    }
+
+    pub fn set_group(&mut self, group: ParticleSystem) {
+        self.group = Some(group);
+    }
+
+    pub fn begin(&mut self, world_pos: Vector2<f32>) {
+        self.start = Some(world_pos);
+    }
+
+    /// Spawns at the drag's start and returns whether anything was added.
+    pub fn finish(
+        &mut self,
+        particles: &mut ParticleSystem,
+        world_pos: Vector2<f32>,
+        kind: SpawnKind,
+    ) -> bool {
+        let Some(center) = self.start.take() else {
+            return false;
+        };
+        let vel = (world_pos - center) * VELOCITY_PER_UNIT;
+        let first = particles.count;
+        // New particles are numbered on from the highest id in use, so ids stay unique.
+        let first_id = particles.next_id();
+        match kind {
+            SpawnKind::Particle => {
+                particles.start_species();
+                particles.add_particle(center, vel, 1.0, 0.001, first_id);
+            }
+            SpawnKind::Galaxy => create_galaxy(
+                particles,
+                &mut self.rng,
+                center,
+                Vector2::zeros(),
+                50.0,
+                10.0,
+                0.01,
+                500,
+            ),
+            SpawnKind::Cluster => spawn_circle(particles, &mut self.rng, center, 30.0, 0.01, 300),
+            SpawnKind::Group => {
+                let Some(group) = &self.group else {
+                    println!("No particle group saved or loaded yet");
+                    return false;
+                };
+                let shift = center - group.center_of_mass();
+                particles.start_species();
+                for i in 0..group.count {
+                    particles.add_particle(
+                        group.get_position(i) + shift,
+                        group.get_velocity(i),
+                        group.mass[i],
+                        group.radius[i],
+                        first_id + i,
+                    );
+                    *particles.color.last_mut().unwrap() = group.color[i];
+                }
+            }
+        }
+        if matches!(kind, SpawnKind::Galaxy | SpawnKind::Cluster) {
+            particles.offset_ids(first, first_id);
+        }
+        // The generators set up the internal motion, the drag moves the group as a whole.
+        if kind != SpawnKind::Particle {
+            for i in first..particles.count {
+                particles.vel_x[i] += vel.x;
+                particles.vel_y[i] += vel.y;
+            }
+        }
+        true
+    }
+
+    /// Draws an arrow from the drag's start to the cursor while dragging.
+    pub fn show_preview(
+        &self,
+        canvas: &mut Canvas,
+        ctx: &mut Context,
+        mouse: Vector2<f32>,
+        offset: Vector2<f32>,
+        zoom: f32,
+    ) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let from = world_to_screen_coords(start, &offset, zoom);
+        let dir = mouse - from;
+        let mut builder = MeshBuilder::new();
+        let _ = builder.circle(
+            ggez::graphics::DrawMode::stroke(2.0),
+            [from.x, from.y],
+            6.0,
+            0.5,
+            Color::GREEN,
+        );
+        let len = dir.norm();
+        if len > 1.0 {
+            let unit = dir / len;
+            let normal = Vector2::new(-unit.y, unit.x);
+            let head = 12.0f32.min(len);
+            let base = mouse - unit * head;
+            let _ = builder.line(&[[from.x, from.y], [base.x, base.y]], 2.0, Color::GREEN);
+            let left = base + normal * head * 0.5;
+            let right = base - normal * head * 0.5;
+            let _ = builder.triangles(
+                &[[mouse.x, mouse.y], [left.x, left.y], [right.x, right.y]],
+                Color::GREEN,
+            );
+        }
+        canvas.draw(&Mesh::from_data(ctx, builder.build()), DrawParam::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn existing() -> ParticleSystem {
+        let mut particles = ParticleSystem::new();
+        for id in [0, 7, 3] {
+            particles.add_particle(Vector2::new(id as f32, 0.0), Vector2::zeros(), 1.0, 0.1, id);
+        }
+        particles
+    }
+
+    fn spawn(particles: &mut ParticleSystem, spawner: &mut Spawner, kind: SpawnKind) {
+        spawner.begin(Vector2::new(100.0, 100.0));
+        assert!(spawner.finish(particles, Vector2::new(110.0, 100.0), kind));
+    }
+
+    #[test]
+    fn spawned_ids_follow_the_highest_id() {
+        let mut spawner = Spawner::new(1);
+        spawner.set_group(existing());
+        for kind in [
+            SpawnKind::Particle,
+            SpawnKind::Galaxy,
+            SpawnKind::Cluster,
+            SpawnKind::Group,
+        ] {
+            let mut particles = existing();
+            spawn(&mut particles, &mut spawner, kind);
+            let new_ids = &particles.indices[3..];
+            assert!(!new_ids.is_empty());
+            assert_eq!(new_ids.iter().min(), Some(&8), "{:?}", kind);
+            assert_eq!(
+                new_ids.iter().max(),
+                Some(&(8 + new_ids.len() - 1)),
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_spawns_keep_ids_unique() {
+        let mut spawner = Spawner::new(1);
+        let mut particles = existing();
+        spawner.set_group(existing());
+        spawn(&mut particles, &mut spawner, SpawnKind::Cluster);
+        spawn(&mut particles, &mut spawner, SpawnKind::Group);
+        spawn(&mut particles, &mut spawner, SpawnKind::Group);
+        let mut ids = particles.indices.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), particles.count);
+    }
+}
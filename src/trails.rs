@@ -0,0 +1,180 @@
+use crate::particle::ParticleSystem;
+use crate::utils::world_to_screen_coords;
+use ggez::graphics::{Canvas, Color, DrawParam, Mesh, MeshData, Vertex};
+use ggez::Context;
+use nalgebra::Vector2;
+use std::collections::VecDeque;
+
+pub const DEFAULT_TRAIL_LENGTH: usize = 30;
+pub const DEFAULT_TRAIL_OPACITY: f32 = 0.5;
+
+/// The last `length` positions of every particle, drawn as lines fading out towards the
+/// oldest position.
+pub struct Trails {
+    pub enabled: bool,
+    pub length: usize,
+    /// Opacity of the newest trail segment.
+    pub opacity: f32,
+    /// Past positions, oldest first, each laid out like the particle columns.
+    history: VecDeque<(Vec<f32>, Vec<f32>)>,
+    last_step: Option<u64>,
+}
+
+impl Trails {
+    pub fn new(enabled: bool, length: usize, opacity: f32) -> Self {
+        Self {
+            enabled,
+            length,
+            opacity,
+            history: VecDeque::with_capacity(length),
+            last_step: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.last_step = None;
+    }
+
+    /// Remembers the current positions. The history starts over when the particles change
+    /// or time runs backwards, e.g. when a replay is rewound.
+    pub fn record(&mut self, particles: &ParticleSystem, step: u64) {
+        if !self.enabled {
+            return;
+        }
+        let changed = self
+            .history
+            .back()
+            .is_some_and(|(x, _)| x.len() != particles.count);
+        if changed || self.last_step.is_some_and(|last| step <= last) {
+            self.history.clear();
+        }
+        while self.history.len() >= self.length.max(1) {
+            self.history.pop_front();
+        }
+        self.history
+            .push_back((particles.pos_x.clone(), particles.pos_y.clone()));
+        self.last_step = Some(step);
+    }
+
+    /// Draws the trails of `particle_list` in one mesh, in the particles' `colors`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw(
+        &self,
+        canvas: &mut Canvas,
+        ctx: &Context,
+        particles: &ParticleSystem,
+        particle_list: &[usize],
+        colors: &[Color],
+        offset: Vector2<f32>,
+        zoom: f32,
+    ) {
+        if !self.enabled || self.history.len() < 2 {
+            return;
+        }
+        let segments = self.history.len() - 1;
+        let mut vertices = Vec::with_capacity(particle_list.len() * segments * 4);
+        let mut indices = Vec::with_capacity(particle_list.len() * segments * 6);
+        for &i in particle_list {
+            if i >= self.history[0].0.len() {
+                continue;
+            }
+            let half_width = particles.screen_radius(i, zoom) / 2.0;
+            let color = colors[i];
+            let point = |k: usize| {
+                let (x, y) = &self.history[k];
+                world_to_screen_coords(Vector2::new(x[i], y[i]), &offset, zoom)
+            };
+            for k in 0..segments {
+                let (from, to) = (point(k), point(k + 1));
+                let dir = to - from;
+                let len = dir.norm();
+                if len < f32::EPSILON {
+                    continue;
+                }
+                let normal = Vector2::new(-dir.y, dir.x) * (half_width / len);
+                let alpha = |k: usize| self.opacity * k as f32 / segments as f32;
+                let (a_from, a_to) = (alpha(k), alpha(k + 1));
+                let base = vertices.len() as u32;
+                for (pos, a) in [
+                    (from + normal, a_from),
+                    (from - normal, a_from),
+                    (to - normal, a_to),
+                    (to + normal, a_to),
+                ] {
+                    vertices.push(Vertex {
+                        position: [pos.x, pos.y],
+                        uv: [0.0, 0.0],
+                        color: [color.r, color.g, color.b, a],
+                    });
+                }
+                indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+        if indices.is_empty() {
+            return;
+        }
+        let mesh = Mesh::from_data(
+            ctx,
+            MeshData {
+                vertices: &vertices,
+                indices: &indices,
+            },
+        );
+        canvas.draw(&mesh, DrawParam::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles_at(x: f32, count: usize) -> ParticleSystem {
+        let mut particles = ParticleSystem::new();
+        for i in 0..count {
+            particles.add_particle(Vector2::new(x, i as f32), Vector2::zeros(), 1.0, 1.0, i);
+        }
+        particles
+    }
+
+    #[test]
+    fn history_keeps_the_last_positions() {
+        let mut trails = Trails::new(true, 3, DEFAULT_TRAIL_OPACITY);
+        for step in 0..5 {
+            trails.record(&particles_at(step as f32, 2), step);
+        }
+        let oldest: Vec<f32> = trails.history.iter().map(|(x, _)| x[0]).collect();
+        assert_eq!(oldest, [2.0, 3.0, 4.0]);
+
+        let mut disabled = Trails::new(false, 3, DEFAULT_TRAIL_OPACITY);
+        disabled.record(&particles_at(0.0, 2), 0);
+        assert!(disabled.history.is_empty());
+    }
+
+    #[test]
+    fn history_starts_over_when_time_runs_backwards() {
+        let mut trails = Trails::new(true, 10, DEFAULT_TRAIL_OPACITY);
+        for step in 0..4 {
+            trails.record(&particles_at(step as f32, 2), step);
+        }
+        trails.record(&particles_at(1.0, 2), 1);
+        assert_eq!(trails.history.len(), 1);
+        assert_eq!(trails.history[0].0[0], 1.0);
+
+        // A repeated step counts as going back too.
+        trails.record(&particles_at(1.0, 2), 1);
+        assert_eq!(trails.history.len(), 1);
+        trails.record(&particles_at(2.0, 2), 2);
+        assert_eq!(trails.history.len(), 2);
+    }
+
+    #[test]
+    fn history_starts_over_when_particles_change() {
+        let mut trails = Trails::new(true, 10, DEFAULT_TRAIL_OPACITY);
+        trails.record(&particles_at(0.0, 2), 0);
+        trails.record(&particles_at(1.0, 2), 1);
+        trails.record(&particles_at(2.0, 3), 2);
+        assert_eq!(trails.history.len(), 1);
+        assert_eq!(trails.history[0].0.len(), 3);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::particle::ParticleSystem;
+use crate::simulation::Clock;
+use chrono::{DateTime, Local};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use ggez::graphics::Color;
+use nalgebra::Vector2;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"GPTRAJ02";
+
+/// Returns a fresh `results/trajectory_<timestamp>_seed<seed>.gptraj` path for a recording.
+pub fn new_trajectory_path(seed: u64) -> PathBuf {
+    let now: DateTime<Local> = Local::now();
+    let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
+    let current_dir = std::env::current_dir().expect("Failed to get current directory");
+    unused_path(
+        &current_dir.join("results"),
+        &format!("trajectory_{}_seed{}", timestamp, seed),
+    )
+}
+
+/// `<stem>.gptraj` in `dir`, or `<stem>_<n>.gptraj` with the first `n` that isn't taken, so
+/// recordings started within the same second don't overwrite each other.
+fn unused_path(dir: &Path, stem: &str) -> PathBuf {
+    let mut path = dir.join(format!("{}.gptraj", stem));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(format!("{}_{}.gptraj", stem, n));
+        n += 1;
+    }
+    path
+}
+
+/// Streams simulation states into a trajectory file.
+///
+/// The file is the magic `GPTRAJ02` and the seed of the scene (u64) followed by frames, each
+/// stored as the step number (u64), the simulated time (f64), the time step (f32), the
+/// compressed length (u32) and a deflated block holding the particle count (u32), the
+/// `x, y, vx, vy, m, r` columns as f32, the particle ids as u64, the species as u32 and the
+/// colors as a flag byte followed by RGBA bytes, all little endian.
+pub struct TrajectoryWriter {
+    out: BufWriter<File>,
+    pub frames: u32,
+}
+
+impl TrajectoryWriter {
+    pub fn create(path: &Path, seed: u64) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&seed.to_le_bytes())?;
+        Ok(Self { out, frames: 0 })
+    }
+
+    pub fn write_frame(&mut self, particles: &ParticleSystem, clock: Clock) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&(particles.count as u32).to_le_bytes())?;
+        for column in [
+            &particles.pos_x,
+            &particles.pos_y,
+            &particles.vel_x,
+            &particles.vel_y,
+            &particles.mass,
+            &particles.radius,
+        ] {
+            for v in column {
+                encoder.write_all(&v.to_le_bytes())?;
+            }
+        }
+        for &id in &particles.indices {
+            encoder.write_all(&(id as u64).to_le_bytes())?;
+        }
+        for species in &particles.species {
+            encoder.write_all(&species.to_le_bytes())?;
+        }
+        for color in &particles.color {
+            let (r, g, b, a) = color.map_or((0, 0, 0, 0), |c| c.to_rgba());
+            encoder.write_all(&[color.is_some() as u8, r, g, b, a])?;
+        }
+        let block = encoder.finish()?;
+
+        self.out.write_all(&clock.steps.to_le_bytes())?;
+        self.out.write_all(&clock.time.to_le_bytes())?;
+        self.out.write_all(&clock.dt.to_le_bytes())?;
+        self.out.write_all(&(block.len() as u32).to_le_bytes())?;
+        self.out.write_all(&block)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct Frame {
+    clock: Clock,
+    block: Vec<u8>,
+}
+
+/// Bytes in front of each frame's compressed block.
+const FRAME_HEADER_BYTES: usize = 8 + 8 + 4 + 4;
+
+/// Bytes each particle takes in a decompressed frame.
+const PARTICLE_BYTES: usize = 6 * 4 + 8 + 4 + 5;
+
+/// A trajectory file loaded into memory, with frames decompressed on demand.
+pub struct Trajectory {
+    /// Seed of the scene that was recorded.
+    pub seed: u64,
+    frames: Vec<Frame>,
+}
+
+impl Trajectory {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if !bytes.starts_with(MAGIC) || bytes.len() < MAGIC.len() + 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a trajectory file",
+            ));
+        }
+        let seed = u64::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 8].try_into().unwrap());
+
+        let mut frames = Vec::new();
+        let mut at = MAGIC.len() + 8;
+        // A recording that was interrupted may end in a partial frame, which is skipped.
+        while at + FRAME_HEADER_BYTES <= bytes.len() {
+            let header = &bytes[at..at + FRAME_HEADER_BYTES];
+            let clock = Clock {
+                steps: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+                time: f64::from_le_bytes(header[8..16].try_into().unwrap()),
+                dt: f32::from_le_bytes(header[16..20].try_into().unwrap()),
+            };
+            let len = u32::from_le_bytes(header[20..24].try_into().unwrap()) as usize;
+            let start = at + FRAME_HEADER_BYTES;
+            let Some(block) = bytes.get(start..start + len) else {
+                break;
+            };
+            frames.push(Frame {
+                clock,
+                block: block.to_vec(),
+            });
+            at = start + len;
+        }
+        Ok(Self { seed, frames })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Decompresses frame `idx`, returning when it was recorded and the particle state.
+    pub fn frame(&self, idx: usize) -> io::Result<(Clock, ParticleSystem)> {
+        let frame = &self.frames[idx];
+        let mut data = Vec::new();
+        DeflateDecoder::new(frame.block.as_slice()).read_to_end(&mut data)?;
+
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "Corrupt trajectory frame");
+        let count =
+            u32::from_le_bytes(data.get(..4).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+        if data.len() != 4 + count * PARTICLE_BYTES {
+            return Err(corrupt());
+        }
+        let column = |c: usize| -> Vec<f32> {
+            let start = 4 + c * count * 4;
+            data[start..start + count * 4]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()
+        };
+        let (x, y, vx, vy, m, r) = (
+            column(0),
+            column(1),
+            column(2),
+            column(3),
+            column(4),
+            column(5),
+        );
+        let ids_start = 4 + 6 * count * 4;
+        let species_start = ids_start + count * 8;
+        let colors_start = species_start + count * 4;
+        let ids = data[ids_start..species_start].chunks_exact(8);
+        let species = data[species_start..colors_start].chunks_exact(4);
+        let colors = data[colors_start..].chunks_exact(5);
+
+        let mut particles = ParticleSystem::with_capacity(count);
+        for (i, ((id, species), color)) in ids.zip(species).zip(colors).enumerate() {
+            particles.add_particle(
+                Vector2::new(x[i], y[i]),
+                Vector2::new(vx[i], vy[i]),
+                m[i],
+                r[i],
+                u64::from_le_bytes(id.try_into().unwrap()) as usize,
+            );
+            *particles.species.last_mut().unwrap() =
+                u32::from_le_bytes(species.try_into().unwrap());
+            if color[0] != 0 {
+                *particles.color.last_mut().unwrap() =
+                    Some(Color::from_rgba(color[1], color[2], color[3], color[4]));
+            }
+        }
+        Ok((frame.clock, particles))
+    }
+}
+
+/// Playback position and controls of a trajectory shown in the GUI.
+pub struct Replay {
+    pub trajectory: Trajectory,
+    /// Fractional frame position, so speeds below one frame per update work.
+    position: f32,
+    /// Frames advanced per update; negative values play backwards.
+    pub speed: f32,
+    pub paused: bool,
+    shown_frame: Option<usize>,
+}
+
+impl Replay {
+    pub fn new(trajectory: Trajectory) -> Self {
+        Self {
+            trajectory,
+            position: 0.0,
+            speed: 1.0,
+            paused: false,
+            shown_frame: None,
+        }
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.position as usize
+    }
+
+    fn last_frame(&self) -> f32 {
+        self.trajectory.len().saturating_sub(1) as f32
+    }
+
+    pub fn advance(&mut self) {
+        if !self.paused {
+            self.position = (self.position + self.speed).clamp(0.0, self.last_frame());
+        }
+    }
+
+    /// Moves by whole frames and pauses, used for single stepping and scrubbing.
+    pub fn step_by(&mut self, frames: i32) {
+        self.paused = true;
+        self.position = (self.position.floor() + frames as f32).clamp(0.0, self.last_frame());
+    }
+
+    pub fn jump_to_start(&mut self) {
+        self.position = 0.0;
+    }
+
+    pub fn jump_to_end(&mut self) {
+        self.position = self.last_frame();
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).clamp(-64.0, 64.0);
+    }
+
+    pub fn slower(&mut self) {
+        let slower = self.speed / 2.0;
+        if slower.abs() >= 1.0 / 64.0 {
+            self.speed = slower;
+        }
+    }
+
+    pub fn reverse(&mut self) {
+        self.speed = -self.speed;
+    }
+
+    /// Returns the frame to display if it differs from the one shown last.
+    pub fn take_changed_frame(&mut self) -> Option<usize> {
+        let frame = self.current_frame();
+        if self.shown_frame == Some(frame) {
+            return None;
+        }
+        self.shown_frame = Some(frame);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gp_trajectory_{}_{}", std::process::id(), name))
+    }
+
+    fn sample(shift: f32) -> ParticleSystem {
+        let mut particles = ParticleSystem::new();
+        particles.add_particle(
+            Vector2::new(1.0 + shift, 2.0),
+            Vector2::new(0.5, -0.5),
+            2.0,
+            0.1,
+            3,
+        );
+        particles.start_species();
+        particles.add_particle(Vector2::new(-4.0, shift), Vector2::zeros(), 1.0e3, 1.5, 8);
+        *particles.color.last_mut().unwrap() = Some(Color::from_rgba(10, 20, 30, 255));
+        particles
+    }
+
+    fn clock(steps: u64) -> Clock {
+        Clock {
+            steps,
+            time: steps as f64 * 0.25,
+            dt: 0.25,
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let path = scratch_path("round_trip");
+        let mut writer = TrajectoryWriter::create(&path, 1234).unwrap();
+        writer.write_frame(&sample(0.0), clock(0)).unwrap();
+        writer.write_frame(&sample(1.0), clock(5)).unwrap();
+        writer.finish().unwrap();
+
+        let trajectory = Trajectory::open(&path).unwrap();
+        assert_eq!(trajectory.seed, 1234);
+        assert_eq!(trajectory.len(), 2);
+        let (frame_clock, particles) = trajectory.frame(1).unwrap();
+        let expected = sample(1.0);
+        assert_eq!(frame_clock, clock(5));
+        assert_eq!(particles.count, expected.count);
+        assert_eq!(particles.pos_x, expected.pos_x);
+        assert_eq!(particles.pos_y, expected.pos_y);
+        assert_eq!(particles.vel_x, expected.vel_x);
+        assert_eq!(particles.vel_y, expected.vel_y);
+        assert_eq!(particles.mass, expected.mass);
+        assert_eq!(particles.radius, expected.radius);
+        assert_eq!(particles.indices, expected.indices);
+        assert_eq!(particles.species, expected.species);
+        assert_eq!(particles.color, expected.color);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn taken_paths_get_a_counter() {
+        let dir = scratch_path("unused");
+        fs::create_dir_all(&dir).unwrap();
+        let first = unused_path(&dir, "trajectory");
+        assert!(first.ends_with("trajectory.gptraj"));
+        fs::write(&first, b"").unwrap();
+        let second = unused_path(&dir, "trajectory");
+        assert!(second.ends_with("trajectory_2.gptraj"));
+        fs::write(&second, b"").unwrap();
+        assert!(unused_path(&dir, "trajectory").ends_with("trajectory_3.gptraj"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_last_frame_is_dropped() {
+        let path = scratch_path("truncated");
+        let mut writer = TrajectoryWriter::create(&path, 0).unwrap();
+        writer.write_frame(&sample(0.0), clock(0)).unwrap();
+        writer.write_frame(&sample(1.0), clock(1)).unwrap();
+        writer.finish().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+
+        let trajectory = Trajectory::open(&path).unwrap();
+        assert_eq!(trajectory.len(), 1);
+        assert_eq!(trajectory.frame(0).unwrap().0, clock(0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wrong_block_length_is_invalid_data() {
+        // Two particles announced, but only one particle's worth of data.
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&2u32.to_le_bytes()).unwrap();
+        encoder.write_all(&[0; PARTICLE_BYTES]).unwrap();
+        let block = encoder.finish().unwrap();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&7u64.to_le_bytes());
+        bytes.extend_from_slice(&7.0f64.to_le_bytes());
+        bytes.extend_from_slice(&1.0f32.to_le_bytes());
+        bytes.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&block);
+        let path = scratch_path("wrong_length");
+        fs::write(&path, bytes).unwrap();
+
+        let trajectory = Trajectory::open(&path).unwrap();
+        assert_eq!(trajectory.len(), 1);
+        let err = trajectory.frame(0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
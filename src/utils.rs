@@ -1,20 +1,31 @@
 
-use crate::consts::{G, MAX_ZOOM, MOUSE_AREA, WORLD_HEIGHT, WORLD_WIDTH};
+use crate::bloom::Bloom;
+use crate::camera::zoom_at;
+use crate::consts::{G, WORLD_HEIGHT, WORLD_WIDTH};
+use crate::galaxy::gaussian;
+use crate::import::load_particles;
 use crate::particle::ParticleSystem;
 use crate::quadtree::QuadTree;
 use crate::rectangle::Rectangle;
 use chrono::{DateTime, Local};
-use ggez::graphics::{ImageEncodingFormat, ScreenImage};
-use ggez::Context;
-use nalgebra::Vector2;
+use ggez::graphics::ScreenImage;
+use ggez::{Context, GameError, GameResult};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, RgbaImage};
+use nalgebra::{Complex, Vector2};
 use rand::Rng;
-use std::fs;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::f64::consts::PI;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::{env, fs};
 
-fn random_in_circle(radius: f32, padding: f32, center: Vector2<f32>) -> Vector2<f32> {
-    let mut rng = rand::thread_rng();
+fn random_in_circle(
+    rng: &mut impl Rng,
+    radius: f32,
+    padding: f32,
+    center: Vector2<f32>,
+) -> Vector2<f32> {
     let angle = rng.gen_range(0.0..2.0 * std::f32::consts::PI);
     let distance = rng.gen_range(padding..radius);
 
@@ -24,21 +35,25 @@ fn random_in_circle(radius: f32, padding: f32, center: Vector2<f32>) -> Vector2<
 #[allow(dead_code)]
 pub fn spawn_circle(
     particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
     center: Vector2<f32>,
     radius: f32,
     particle_mass: f32,
     particles_amount: i32,
 ) {
+    particles.start_species();
     for i in 0..particles_amount {
-        let pos = random_in_circle(radius, 0.0, center);
+        let pos = random_in_circle(rng, radius, 0.0, center);
         particles.add_particle(pos, Vector2::default(), particle_mass, 0.00001, i as usize);
     }
 }
 
 
 #[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
 pub fn create_galaxy(
     particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
     center: Vector2<f32>,
     initial_vel: Vector2<f32>,
     radius: f32,
@@ -46,8 +61,9 @@ pub fn create_galaxy(
     particle_mass: f32,
     particles_amount: i32,
 ) {
+    particles.start_species();
     for i in 0..particles_amount {
-        let pos = random_in_circle(radius, 2.0, center);
+        let pos = random_in_circle(rng, radius, 2.0, center);
         let distance_to_center = pos.metric_distance(&center);
         let orbital_vel = ((G * sun_mass) / distance_to_center).sqrt();
         let dir = Vector2::new(pos.y - center.y, center.x - pos.x).normalize();
@@ -63,9 +79,24 @@ pub fn create_galaxy(
         particles_amount as usize,
     );
 }
+
+/// Appends particles read from a CSV/NPY/NPZ file, see `import::load_particles` for the layout.
+/// A file that can't be read or fails validation ends the program with the import error.
+pub fn load_initial_conditions(particles: &mut ParticleSystem, path: &Path) {
+    match load_particles(particles, path) {
+        Ok(count) => println!("Loaded {} particles from {}", count, path.display()),
+        Err(e) => {
+            eprintln!("Failed to load particles from {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
 #[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
 pub fn create_square(
     particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
     center: Vector2<f32>,
     side: f32,
     particle_mass: f32,
@@ -73,7 +104,7 @@ pub fn create_square(
     velocity_spread: f32,
     particles_amount: i32,
 ) {
-    let mut rng = rand::thread_rng();
+    particles.start_species();
     let half = side / 2.0;
 
     for i in 0..particles_amount {
@@ -94,6 +125,7 @@ pub fn create_square(
 #[allow(dead_code)]
 pub fn create_square_default(
     particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
     center: Vector2<f32>,
     side: f32,
     particle_mass: f32,
@@ -104,6 +136,7 @@ pub fn create_square_default(
     let spread = average_velocity.norm() * 0.3;
     create_square(
         particles,
+        rng,
         center,
         side,
         particle_mass,
@@ -112,6 +145,172 @@ pub fn create_square_default(
         particles_amount,
     );
 }
+
+/// Cold collapse test: a disk of uniform surface density where every particle starts with the
+/// same velocity.
+pub fn create_uniform_disk(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    center: Vector2<f32>,
+    initial_vel: Vector2<f32>,
+    radius: f32,
+    total_mass: f32,
+    particles_amount: i32,
+) {
+    particles.start_species();
+    let particle_mass = total_mass / particles_amount as f32;
+    for i in 0..particles_amount {
+        let angle = rng.gen_range(0.0..2.0 * std::f32::consts::PI);
+        // The square root keeps the density uniform in area rather than in radius.
+        let distance = radius * rng.gen_range(0.0f32..1.0).sqrt();
+        let pos = center + Vector2::new(distance * angle.cos(), distance * angle.sin());
+        particles.add_particle(pos, initial_vel, particle_mass, 0.001, i as usize);
+    }
+}
+
+/// Square lattice displaced by a Gaussian random field with a power law spectrum, using the
+/// Zel'dovich approximation.
+#[derive(Clone, Debug)]
+pub struct PerturbedLattice {
+    pub center: Vector2<f32>,
+    /// Side length of the square box the lattice fills.
+    pub side: f32,
+    /// Lattice points per side, at most [`MAX_LATTICE_CELLS`].
+    pub cells: usize,
+    pub total_mass: f32,
+    /// Slope `n` of the density power spectrum `P(k) ∝ k^n`.
+    pub spectral_index: f32,
+    /// Root mean square displacement, in lattice spacings.
+    pub rms_displacement: f32,
+    /// Velocity per unit displacement, the growth rate of the perturbations per step.
+    pub growth_rate: f32,
+}
+
+impl Default for PerturbedLattice {
+    fn default() -> Self {
+        Self {
+            center: Vector2::new(0.0, 0.0),
+            side: 400.0,
+            cells: 64,
+            total_mass: 50.0,
+            spectral_index: -1.0,
+            rms_displacement: 0.3,
+            growth_rate: 0.002,
+        }
+    }
+}
+
+/// Largest lattice `create_perturbed_lattice` accepts. The direct DFT costs O(n³) per
+/// transform, so bigger grids would take seconds to set up.
+pub const MAX_LATTICE_CELLS: usize = 256;
+
+/// In-place discrete Fourier transform of an `n` by `n` grid, row by row and then column by
+/// column. The lattices are small, so the direct O(n³) sum is fast enough.
+fn dft_2d(grid: &mut [Complex<f64>], n: usize, inverse: bool) {
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let twiddles: Vec<Complex<f64>> = (0..n)
+        .map(|j| {
+            let angle = sign * 2.0 * PI * j as f64 / n as f64;
+            Complex::new(angle.cos(), angle.sin())
+        })
+        .collect();
+    let mut line = vec![Complex::new(0.0, 0.0); n];
+    for (stride, step) in [(n, 1), (1, n)] {
+        for start in 0..n {
+            let at = |i: usize| start * stride + i * step;
+            for (k, out) in line.iter_mut().enumerate() {
+                *out = (0..n).map(|j| grid[at(j)] * twiddles[j * k % n]).sum();
+            }
+            for (i, value) in line.iter().enumerate() {
+                grid[at(i)] = *value;
+            }
+        }
+    }
+}
+
+/// Builds the lattice described by `lattice`. The displacement field is periodic over the box,
+/// so the setup also tiles seamlessly under periodic boundaries.
+pub fn create_perturbed_lattice(
+    particles: &mut ParticleSystem,
+    rng: &mut impl Rng,
+    lattice: &PerturbedLattice,
+) {
+    let n = lattice.cells;
+    if n == 0 {
+        return;
+    }
+    assert!(
+        n <= MAX_LATTICE_CELLS,
+        "A perturbed lattice has at most {} cells per side",
+        MAX_LATTICE_CELLS
+    );
+    let spacing = lattice.side / n as f32;
+    particles.start_species();
+
+    // White noise in real space gives Fourier modes with the right symmetry for a real field.
+    let mut noise: Vec<Complex<f64>> = (0..n * n)
+        .map(|_| Complex::new(gaussian(rng), 0.0))
+        .collect();
+    dft_2d(&mut noise, n, false);
+
+    // The displacement is the gradient of the potential: ψ(k) = i k / k² δ(k).
+    let mut psi_x = vec![Complex::new(0.0, 0.0); n * n];
+    let mut psi_y = vec![Complex::new(0.0, 0.0); n * n];
+    let wavenumber = |i: usize| {
+        if i <= n / 2 {
+            i as f64
+        } else {
+            i as f64 - n as f64
+        }
+    };
+    for row in 0..n {
+        for column in 0..n {
+            let (kx, ky) = (wavenumber(column), wavenumber(row));
+            let k_sq = kx * kx + ky * ky;
+            // Skip the mean and the Nyquist modes, which have no well defined direction.
+            if k_sq == 0.0 || (n.is_multiple_of(2) && (column == n / 2 || row == n / 2)) {
+                continue;
+            }
+            let amplitude = k_sq.powf(lattice.spectral_index as f64 / 4.0);
+            let delta = noise[row * n + column] * amplitude;
+            let i_delta = Complex::new(-delta.im, delta.re) / k_sq;
+            psi_x[row * n + column] = i_delta * kx;
+            psi_y[row * n + column] = i_delta * ky;
+        }
+    }
+    dft_2d(&mut psi_x, n, true);
+    dft_2d(&mut psi_y, n, true);
+
+    let mean_sq = psi_x
+        .iter()
+        .zip(&psi_y)
+        .map(|(x, y)| x.re * x.re + y.re * y.re)
+        .sum::<f64>()
+        / (n * n) as f64;
+    let scale = if mean_sq > 0.0 {
+        (lattice.rms_displacement * spacing) as f64 / mean_sq.sqrt()
+    } else {
+        0.0
+    };
+
+    let corner = lattice.center - Vector2::new(lattice.side, lattice.side) / 2.0;
+    let particle_mass = lattice.total_mass / (n * n) as f32;
+    for row in 0..n {
+        for column in 0..n {
+            let idx = row * n + column;
+            let grid_pos = corner + Vector2::new(column as f32 + 0.5, row as f32 + 0.5) * spacing;
+            let displacement =
+                Vector2::new(psi_x[idx].re * scale, psi_y[idx].re * scale).cast::<f32>();
+            particles.add_particle(
+                grid_pos + displacement,
+                displacement * lattice.growth_rate,
+                particle_mass,
+                0.001,
+                idx,
+            );
+        }
+    }
+}
 pub fn create_quadtree(particles: &ParticleSystem) -> QuadTree {
     let mut qt = QuadTree::new(Rectangle::new(
         Vector2::new(0.0, 0.0),
@@ -140,9 +339,8 @@ pub fn screen_to_world_coords(
     screen_coords / zoom - origin//ctx.mouse.position()
 }
 
+#[allow(clippy::needless_return)]
 pub fn move_on_mouse(ctx: &mut Context, origin: &mut Vector2<f32>, zoom: f32) {
-    let mouse_pos = ctx.mouse.position();
-    let (mouse_x, mouse_y) = (mouse_pos.x, mouse_pos.y);
     if ctx
         .mouse
         .button_pressed(ggez::input::mouse::MouseButton::Left)
@@ -152,21 +350,6 @@ pub fn move_on_mouse(ctx: &mut Context, origin: &mut Vector2<f32>, zoom: f32) {
         origin.x += delta_x / zoom;
         origin.y += delta_y / zoom;
     }
-
-    if ctx
-        .mouse
-        .button_pressed(ggez::input::mouse::MouseButton::Middle)
-    {
-        let screen_coords = Vector2::new(mouse_x, mouse_y);
-        let world_coords = screen_to_world_coords(screen_coords, origin, zoom);
-        if world_coords.x < 0.0
-            || world_coords.x > WORLD_WIDTH
-            || world_coords.y < 0.0
-            || world_coords.y > WORLD_HEIGHT
-        {
-            return;
-        }
-    }
 }
 
 pub fn zoom_world(
@@ -177,34 +360,61 @@ pub fn zoom_world(
 ) {
     let scale_factor = 1.1;
     let mouse_pos = ctx.mouse.position();
-    let (mouse_x, mouse_y) = (mouse_pos.x, mouse_pos.y);
-    let mouse_world_before = screen_to_world_coords(Vector2::new(mouse_x, mouse_y), origin, *zoom);
+    let mouse = Vector2::new(mouse_pos.x, mouse_pos.y);
 
     if wheel_direction > 0.0 {
-        *zoom = (*zoom * scale_factor);//.min(MAX_ZOOM);
+        zoom_at(origin, zoom, mouse, scale_factor);
     } else if wheel_direction < 0.0 {
-        *zoom = (*zoom / scale_factor);//.min(MOUSE_AREA);
+        zoom_at(origin, zoom, mouse, 1.0 / scale_factor);
     }
-
-    let mouse_world_after = screen_to_world_coords(Vector2::new(mouse_x, mouse_y), origin, *zoom);
-    origin.x = mouse_world_before.x - mouse_world_after.x;
-    origin.y = mouse_world_before.y - mouse_world_after.y;
-
-    //origin.x = origin.x.clamp(-WORLD_WIDTH / 2.0, WORLD_WIDTH / 2.0);
-    //origin.y = origin.y.clamp(-WORLD_HEIGHT / 2.0, WORLD_HEIGHT / 2.0);
 }
 
-pub fn save_screen(ctx: &mut Context, screen: &mut ScreenImage, frame_count: u32) {
+pub fn save_screen(ctx: &mut Context, screen: &mut ScreenImage, frame_count: u32, bloom: &Bloom) {
     let path = format!("/image-cache/frame-{}.jpg", frame_count);
-    let result = screen
-        .image(ctx)
-        .encode(ctx, ImageEncodingFormat::Jpeg, path.as_str());
-    match result {
+    match encode_screen(ctx, screen, &path, bloom) {
         Ok(_) => {}
         Err(e) => eprintln!("Error saving screen: {:?}", e),
     }
 }
 
+/// Reads the screen back from the GPU so the bloom pass can run on it before it is encoded.
+fn encode_screen(
+    ctx: &mut Context,
+    screen: &mut ScreenImage,
+    path: &str,
+    bloom: &Bloom,
+) -> GameResult {
+    let image = screen.image(ctx);
+    let mut frame = RgbaImage::from_raw(image.width(), image.height(), image.to_pixels(ctx)?)
+        .ok_or_else(|| GameError::RenderError("Unexpected screen pixel layout".to_string()))?;
+    bloom.apply(&mut frame);
+    let mut writer = BufWriter::new(ctx.fs.create(path)?);
+    JpegEncoder::new_with_quality(&mut writer, 95).encode(
+        frame.as_raw(),
+        frame.width(),
+        frame.height(),
+        ColorType::Rgba8,
+    )?;
+    Ok(())
+}
+
+pub fn create_results_dir() {
+    let directory_name = "results";
+    let current_dir = env::current_dir().expect("Failed to get current directory");
+    let new_directory_path = current_dir.join(directory_name);
+    match fs::metadata(&new_directory_path) {
+        Ok(_) => println!("Results folder already exists"),
+        Err(_) => match fs::create_dir(&new_directory_path) {
+            Ok(_) => {
+                println!("Created initial results folder");
+            }
+            Err(e) => {
+                eprintln!("Error creating directory: {}", e);
+            }
+        },
+    }
+}
+
 pub fn rename_images(ctx: &Context) {
     let cache_dir_path: PathBuf = ctx.fs.resources_dir().join("image-cache");
     if !cache_dir_path.exists() || !cache_dir_path.is_dir() {
@@ -242,16 +452,16 @@ pub fn rename_images(ctx: &Context) {
     for (index, old_path) in cache_pics.iter().enumerate() {
         let new_name = format!("{:06}.jpg", index + 1);
         let new_path = cache_dir_path.join(new_name);
-        if let Err(e) = fs::rename(&old_path, &new_path) {
+        if let Err(e) = fs::rename(old_path, &new_path) {
             eprintln!("Error renaming file: {:?}", e);
         }
     }
 }
 
-pub fn convert_to_video(ctx: &Context) {
+pub fn convert_to_video(ctx: &Context, seed: u64) {
     let now: DateTime<Local> = Local::now();
     let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
-    let output_filename = format!("output_{}.mp4", timestamp);
+    let output_filename = format!("output_{}_seed{}.mp4", timestamp, seed);
     let current_dir = std::env::current_dir().expect("Failed to get current directory");
     let results_path = current_dir.join("results").join(output_filename);
 
@@ -259,13 +469,13 @@ pub fn convert_to_video(ctx: &Context) {
     let input_pattern = cache_dir_path.join("%06d.jpg");
 
     let mut cmd = Command::new("ffmpeg")
-        .args(&["-y"])
-        .args(&["-framerate", "60"])
-        .args(&["-i", input_pattern.to_str().expect("Invalid path")])
-        .args(&["-c:v", "libx264"])
-        .args(&["-pix_fmt", "yuv420p"])
-        .args(&["-preset", "veryfast"])
-        .args(&["-crf", "18"])
+        .args(["-y"])
+        .args(["-framerate", "60"])
+        .args(["-i", input_pattern.to_str().expect("Invalid path")])
+        .args(["-c:v", "libx264"])
+        .args(["-pix_fmt", "yuv420p"])
+        .args(["-preset", "veryfast"])
+        .args(["-crf", "18"])
         .arg(results_path.to_str().expect("Invalid path"))
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -274,10 +484,8 @@ pub fn convert_to_video(ctx: &Context) {
 
     let stderr = cmd.stderr.take().expect("Failed to capture stderr");
     let reader = BufReader::new(stderr);
-    for line in reader.lines() {
-        if let Ok(line) = line {
-            println!("{}", line);
-        }
+    for line in reader.lines().map_while(Result::ok) {
+        println!("{}", line);
     }
 
     match cmd.wait() {
@@ -299,14 +507,116 @@ pub fn clean_cache_images(ctx: &Context) {
     }
 
     let entries = fs::read_dir(&cache_dir_path).expect("Failed to read cache directory");
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            if path.is_file() && path.extension() == Some(std::ffi::OsStr::new("jpg")) {
-                if let Err(e) = fs::remove_file(&path) {
-                    eprintln!("Error deleting file: {:?}", e);
-                }
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_file() && path.extension() == Some(std::ffi::OsStr::new("jpg")) {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Error deleting file: {:?}", e);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn dft_round_trip_restores_the_grid() {
+        let n = 6;
+        let original: Vec<Complex<f64>> = (0..n * n)
+            .map(|i| Complex::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos()))
+            .collect();
+        let mut grid = original.clone();
+        dft_2d(&mut grid, n, false);
+        dft_2d(&mut grid, n, true);
+        for (value, expected) in grid.iter().zip(&original) {
+            assert!((value / (n * n) as f64 - expected).norm_sqr() < 1e-24);
+        }
+    }
+
+    #[test]
+    fn lattice_is_displaced_by_the_requested_amount() {
+        let lattice = PerturbedLattice {
+            center: Vector2::new(100.0, -50.0),
+            cells: 16,
+            ..PerturbedLattice::default()
+        };
+        let mut particles = ParticleSystem::new();
+        create_perturbed_lattice(&mut particles, &mut StdRng::seed_from_u64(6), &lattice);
+        let n = lattice.cells;
+        assert_eq!(particles.count, n * n);
+
+        let spacing = lattice.side / n as f32;
+        let corner = lattice.center - Vector2::new(lattice.side, lattice.side) / 2.0;
+        let (mut sum, mut sum_sq) = (Vector2::zeros(), 0.0);
+        for idx in 0..particles.count {
+            let (row, column) = (idx / n, idx % n);
+            let grid_pos = corner + Vector2::new(column as f32 + 0.5, row as f32 + 0.5) * spacing;
+            let displacement = (particles.get_position(idx) - grid_pos).cast::<f64>();
+            let vel = Vector2::new(particles.vel_x[idx], particles.vel_y[idx]).cast::<f64>();
+            assert!((vel - displacement * lattice.growth_rate as f64).norm() < 1e-5);
+            assert_eq!(particles.indices[idx], idx);
+            sum += displacement;
+            sum_sq += displacement.norm_squared();
+        }
+
+        // The mean mode is left out, so the lattice as a whole stays in place.
+        let count = particles.count as f64;
+        assert!(
+            (sum / count).norm() < 1e-3,
+            "mean displacement {}",
+            sum / count
+        );
+        let rms = (sum_sq / count).sqrt() / spacing as f64;
+        assert!((rms / lattice.rms_displacement as f64 - 1.0).abs() < 1e-3);
+        let total: f32 = particles.mass.iter().sum();
+        assert!((total / lattice.total_mass - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn steeper_spectra_move_more_power_to_large_scales() {
+        // Neighbours move together when the long waves dominate.
+        let neighbour_correlation = |spectral_index: f32| {
+            let lattice = PerturbedLattice {
+                cells: 32,
+                spectral_index,
+                ..PerturbedLattice::default()
+            };
+            let mut particles = ParticleSystem::new();
+            create_perturbed_lattice(&mut particles, &mut StdRng::seed_from_u64(7), &lattice);
+            let n = lattice.cells;
+            let spacing = lattice.side / n as f32;
+            let displacement = |idx: usize| {
+                let grid_x = (idx % n) as f32 + 0.5;
+                (particles.pos_x[idx] - lattice.center.x + lattice.side / 2.0) / spacing - grid_x
+            };
+            let (mut product, mut square) = (0.0, 0.0);
+            for idx in 0..n * n {
+                let right = idx - idx % n + (idx + 1) % n;
+                product += displacement(idx) * displacement(right);
+                square += displacement(idx) * displacement(idx);
+            }
+            product / square
+        };
+        let steep = neighbour_correlation(-2.0);
+        let flat = neighbour_correlation(1.0);
+        assert!(steep > flat + 0.2, "{} vs {}", steep, flat);
+    }
+
+    #[test]
+    #[should_panic(expected = "at most")]
+    fn oversized_lattices_are_rejected() {
+        let lattice = PerturbedLattice {
+            cells: MAX_LATTICE_CELLS + 1,
+            ..PerturbedLattice::default()
+        };
+        create_perturbed_lattice(
+            &mut ParticleSystem::new(),
+            &mut StdRng::seed_from_u64(8),
+            &lattice,
+        );
+    }
+}